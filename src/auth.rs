@@ -0,0 +1,135 @@
+use crate::player_name_to_user_data;
+use bevy_renet::renet::transport::{
+    generate_random_bytes, ConnectToken, NetcodeError, TokenGenerationError, NETCODE_KEY_BYTES,
+};
+use std::{
+    fmt, fs, io,
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+pub type PrivateKey = [u8; NETCODE_KEY_BYTES];
+
+/// Seconds without packets before either side of a secure connection times out.
+const TOKEN_TIMEOUT_SECONDS: i32 = 15;
+
+#[derive(Debug)]
+pub enum AuthError {
+    InvalidHex,
+    InvalidKeyLength(usize),
+    Io(io::Error),
+    Netcode(NetcodeError),
+    Token(TokenGenerationError),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::InvalidHex => write!(f, "expected a hex encoded string"),
+            AuthError::InvalidKeyLength(len) => write!(
+                f,
+                "private key must be {} bytes, got {}",
+                NETCODE_KEY_BYTES, len
+            ),
+            AuthError::Io(err) => write!(f, "{}", err),
+            AuthError::Netcode(err) => write!(f, "invalid connect token: {}", err),
+            AuthError::Token(err) => write!(f, "could not generate connect token: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<io::Error> for AuthError {
+    fn from(err: io::Error) -> Self {
+        AuthError::Io(err)
+    }
+}
+
+impl From<NetcodeError> for AuthError {
+    fn from(err: NetcodeError) -> Self {
+        AuthError::Netcode(err)
+    }
+}
+
+impl From<TokenGenerationError> for AuthError {
+    fn from(err: TokenGenerationError) -> Self {
+        AuthError::Token(err)
+    }
+}
+
+pub fn generate_private_key() -> PrivateKey {
+    generate_random_bytes()
+}
+
+pub fn parse_private_key(hex: &str) -> Result<PrivateKey, AuthError> {
+    let bytes = decode_hex(hex)?;
+    bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| AuthError::InvalidKeyLength(bytes.len()))
+}
+
+/// Issues a token that lets `client_id` join any of `server_addresses` as `player_name`.
+pub fn issue_token(
+    private_key: &PrivateKey,
+    protocol_id: u64,
+    client_id: u64,
+    player_name: &str,
+    server_addresses: Vec<SocketAddr>,
+    expire_seconds: u64,
+) -> Result<ConnectToken, AuthError> {
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO);
+    let user_data = player_name_to_user_data(player_name);
+
+    Ok(ConnectToken::generate(
+        current_time,
+        protocol_id,
+        expire_seconds,
+        client_id,
+        TOKEN_TIMEOUT_SECONDS,
+        server_addresses,
+        Some(&user_data),
+        private_key,
+    )?)
+}
+
+pub fn encode_token(token: &ConnectToken) -> String {
+    let mut bytes = Vec::new();
+    token.write(&mut bytes).unwrap();
+    encode_hex(&bytes)
+}
+
+pub fn decode_token(blob: &str) -> Result<ConnectToken, AuthError> {
+    let bytes = decode_hex(blob.trim())?;
+    Ok(ConnectToken::read(&mut bytes.as_slice())?)
+}
+
+pub fn write_token_file(token: &ConnectToken, path: impl AsRef<Path>) -> Result<(), AuthError> {
+    let mut file = fs::File::create(path)?;
+    token.write(&mut file)?;
+    Ok(())
+}
+
+pub fn read_token_file(path: impl AsRef<Path>) -> Result<ConnectToken, AuthError> {
+    let mut file = fs::File::open(path)?;
+    Ok(ConnectToken::read(&mut file)?)
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn decode_hex(hex: &str) -> Result<Vec<u8>, AuthError> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(AuthError::InvalidHex);
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| AuthError::InvalidHex))
+        .collect()
+}
@@ -1,6 +1,16 @@
 use bevy::prelude::*;
-use medieval_call_of_duty::client::ClientPlugin;
+use medieval_call_of_duty::client::{ClientConfig, ClientPlugin};
+use std::process::ExitCode;
 
-fn main() {
-    App::new().add_plugins(ClientPlugin).run();
+fn main() -> ExitCode {
+    let config = match ClientConfig::from_args() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Could not load client config: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    App::new().add_plugins(ClientPlugin { config }).run();
+    ExitCode::SUCCESS
 }
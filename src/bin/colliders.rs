@@ -0,0 +1,71 @@
+use bevy::{
+    app::{AppExit, ScheduleRunnerPlugin},
+    prelude::*,
+    winit::WinitPlugin,
+};
+use bevy_asset_loader::prelude::*;
+use clap::Parser;
+use medieval_call_of_duty::map::{ColliderCache, MapLoaded, MapPlugin, WorldAssets, DEFAULT_MAP};
+use std::path::PathBuf;
+
+/// Computes the colliders of a map ahead of time, so the server can start from the cache.
+#[derive(Parser, Debug, Resource)]
+struct CollidersArgs {
+    /// Asset path of the map glTF.
+    #[arg(long, default_value = DEFAULT_MAP)]
+    map: String,
+    /// Cache file to write, passed to the server with `--collider-cache`. Up to date colliders
+    /// already in it are kept.
+    #[arg(long)]
+    output: PathBuf,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Hash, Default, States)]
+enum CollidersStates {
+    #[default]
+    AssetLoading,
+    Loaded,
+}
+
+fn main() {
+    let args = CollidersArgs::parse();
+    let collider_cache = args.output.exists().then(|| args.output.clone());
+
+    App::new()
+        .add_plugins(DefaultPlugins.build().disable::<WinitPlugin>())
+        .add_plugins(ScheduleRunnerPlugin::default())
+        .add_plugins(MapPlugin {
+            path: args.map.clone(),
+            collider_cache,
+        })
+        .add_state::<CollidersStates>()
+        .add_loading_state(
+            LoadingState::new(CollidersStates::AssetLoading)
+                .continue_to_state(CollidersStates::Loaded)
+                .load_collection::<WorldAssets>(),
+        )
+        .insert_resource(args)
+        .add_systems(Update, write_cache)
+        .run();
+}
+
+fn write_cache(
+    mut loaded: EventReader<MapLoaded>,
+    cache: Res<ColliderCache>,
+    args: Res<CollidersArgs>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if loaded.read().next().is_none() {
+        return;
+    }
+
+    match cache.write(&args.output) {
+        Ok(()) => println!(
+            "Wrote {} colliders to {}",
+            cache.colliders.len(),
+            args.output.display()
+        ),
+        Err(err) => println!("Could not write {}: {}", args.output.display(), err),
+    }
+    exit.send(AppExit);
+}
@@ -1,6 +1,19 @@
 use bevy::prelude::*;
-use medieval_call_of_duty::server::ServerPlugin;
+use medieval_call_of_duty::server::{ServerConfig, ServerPlugin, ServerStartError};
+use std::process::ExitCode;
 
-fn main() {
-    App::new().add_plugins(ServerPlugin).run();
+fn main() -> ExitCode {
+    let plugin = ServerConfig::from_args()
+        .map_err(ServerStartError::from)
+        .and_then(ServerPlugin::new);
+    let plugin = match plugin {
+        Ok(plugin) => plugin,
+        Err(err) => {
+            eprintln!("Could not start server: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    App::new().add_plugins(plugin).run();
+    ExitCode::SUCCESS
 }
@@ -0,0 +1,91 @@
+use bevy_renet::renet::transport::generate_random_bytes;
+use clap::Parser;
+use medieval_call_of_duty::{
+    auth::{
+        encode_hex, encode_token, generate_private_key, issue_token, parse_private_key,
+        write_token_file,
+    },
+    PROTOCOL_ID,
+};
+use std::{net::SocketAddr, path::PathBuf, process::ExitCode};
+
+/// Issues connect tokens for servers running in secure authentication mode.
+#[derive(Parser, Debug)]
+struct TokenArgs {
+    /// Print a new private key and exit.
+    #[arg(long)]
+    generate_key: bool,
+    /// Hex encoded private key shared with the server.
+    #[arg(
+        long,
+        env = "MCOD_PRIVATE_KEY",
+        hide_env_values = true,
+        required_unless_present = "generate_key"
+    )]
+    private_key: Option<String>,
+    #[arg(long, default_value = "Player")]
+    player_name: String,
+    /// Defaults to a random id.
+    #[arg(long)]
+    client_id: Option<u64>,
+    /// Server the token is valid for, can be repeated.
+    #[arg(long = "server-addr", default_value = "127.0.0.1:5000")]
+    server_addrs: Vec<SocketAddr>,
+    #[arg(long, env = "MCOD_PROTOCOL_ID", default_value_t = PROTOCOL_ID)]
+    protocol_id: u64,
+    #[arg(long, default_value_t = 300)]
+    expire_seconds: u64,
+    /// Write the token to a file instead of printing it as hex.
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let args = TokenArgs::parse();
+
+    if args.generate_key {
+        println!("{}", encode_hex(&generate_private_key()));
+        return ExitCode::SUCCESS;
+    }
+
+    let private_key = match parse_private_key(&args.private_key.unwrap()) {
+        Ok(private_key) => private_key,
+        Err(err) => {
+            eprintln!("Invalid private key: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let client_id = args
+        .client_id
+        .unwrap_or_else(|| u64::from_le_bytes(generate_random_bytes()));
+    let token = match issue_token(
+        &private_key,
+        args.protocol_id,
+        client_id,
+        &args.player_name,
+        args.server_addrs,
+        args.expire_seconds,
+    ) {
+        Ok(token) => token,
+        Err(err) => {
+            eprintln!("Could not issue connect token: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match args.output {
+        Some(path) => {
+            if let Err(err) = write_token_file(&token, &path) {
+                eprintln!(
+                    "Could not write connect token to {}: {}",
+                    path.display(),
+                    err
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+        None => println!("{}", encode_token(&token)),
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,248 @@
+use crate::codec::{decode, DecodeError};
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_renet::{
+    renet::{ChannelConfig, ClientId, ConnectionConfig, RenetClient, RenetServer, SendType},
+    transport::NetcodeClientPlugin,
+    RenetReceive,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    any::{type_name, TypeId},
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+};
+
+/// Memory each channel may hold in queued messages.
+const MAX_CHANNEL_MEMORY_BYTES: usize = 10 * 1024 * 1024;
+
+pub trait NetworkMessage: Serialize + DeserializeOwned + Send + Sync + 'static {}
+
+impl<T: Serialize + DeserializeOwned + Send + Sync + 'static> NetworkMessage for T {}
+
+/// Channel of every message type, one per type. Channel ids follow registration order, so the
+/// client and the server must register the same types in the same order.
+#[derive(Debug, Default, Clone, Resource)]
+pub struct ChannelRegistry {
+    client_channels: Vec<ChannelConfig>,
+    server_channels: Vec<ChannelConfig>,
+    channel_ids: HashMap<TypeId, u8>,
+}
+
+impl ChannelRegistry {
+    pub fn channel_id<T: NetworkMessage>(&self) -> u8 {
+        match self.channel_ids.get(&TypeId::of::<T>()) {
+            Some(channel_id) => *channel_id,
+            None => panic!("{} is not a registered network message", type_name::<T>()),
+        }
+    }
+
+    pub fn connection_config(&self) -> ConnectionConfig {
+        ConnectionConfig {
+            available_bytes_per_tick: 1024 * 1024,
+            client_channels_config: self.client_channels.clone(),
+            server_channels_config: self.server_channels.clone(),
+        }
+    }
+
+    fn register<T: NetworkMessage>(&mut self, send_type: SendType, from_client: bool) {
+        let channels = if from_client {
+            &mut self.client_channels
+        } else {
+            &mut self.server_channels
+        };
+        let channel_id = channels.len() as u8;
+        let previous = self.channel_ids.insert(TypeId::of::<T>(), channel_id);
+        assert!(
+            previous.is_none(),
+            "{} is registered twice",
+            type_name::<T>()
+        );
+
+        channels.push(ChannelConfig {
+            channel_id,
+            max_memory_usage_bytes: MAX_CHANNEL_MEMORY_BYTES,
+            send_type,
+        });
+    }
+}
+
+/// A message received from a client.
+#[derive(Debug, Event)]
+pub struct FromClient<T: NetworkMessage> {
+    pub client_id: ClientId,
+    pub message: T,
+}
+
+/// A message received from the server.
+#[derive(Debug, Event)]
+pub struct FromServer<T: NetworkMessage> {
+    pub message: T,
+}
+
+/// A message from a client that could not be decoded.
+#[derive(Debug, Event)]
+pub struct InvalidMessage {
+    pub client_id: ClientId,
+    pub error: DecodeError,
+}
+
+pub trait NetworkMessageAppExt {
+    /// Registers a message sent by clients. On the server every received message becomes a
+    /// [`FromClient`] event.
+    fn add_client_message<T: NetworkMessage>(&mut self, send_type: SendType) -> &mut Self;
+
+    /// Registers a message sent by the server. On clients every received message becomes a
+    /// [`FromServer`] event.
+    fn add_server_message<T: NetworkMessage>(&mut self, send_type: SendType) -> &mut Self;
+}
+
+impl NetworkMessageAppExt for App {
+    fn add_client_message<T: NetworkMessage>(&mut self, send_type: SendType) -> &mut Self {
+        self.init_resource::<ChannelRegistry>()
+            .world
+            .resource_mut::<ChannelRegistry>()
+            .register::<T>(send_type, true);
+
+        self.add_event::<FromClient<T>>()
+            .add_event::<InvalidMessage>()
+            .add_systems(
+                PreUpdate,
+                receive_from_clients::<T>
+                    .after(RenetReceive)
+                    .run_if(resource_exists::<RenetServer>()),
+            )
+    }
+
+    fn add_server_message<T: NetworkMessage>(&mut self, send_type: SendType) -> &mut Self {
+        self.init_resource::<ChannelRegistry>()
+            .world
+            .resource_mut::<ChannelRegistry>()
+            .register::<T>(send_type, false);
+
+        self.add_event::<FromServer<T>>().add_systems(
+            PreUpdate,
+            receive_from_server::<T>
+                .after(NetcodeClientPlugin::update_system)
+                .run_if(resource_exists::<RenetClient>()),
+        )
+    }
+}
+
+/// [`RenetServer`] sending and receiving typed messages on their registered channels.
+#[derive(SystemParam)]
+pub struct NetworkServer<'w> {
+    server: ResMut<'w, RenetServer>,
+    registry: Res<'w, ChannelRegistry>,
+}
+
+impl NetworkServer<'_> {
+    pub fn send<T: NetworkMessage>(&mut self, client_id: ClientId, message: &T) {
+        let channel_id = self.registry.channel_id::<T>();
+        self.server
+            .send_message(client_id, channel_id, bincode::serialize(message).unwrap());
+    }
+
+    pub fn broadcast<T: NetworkMessage>(&mut self, message: &T) {
+        let channel_id = self.registry.channel_id::<T>();
+        self.server
+            .broadcast_message(channel_id, bincode::serialize(message).unwrap());
+    }
+
+    pub fn broadcast_except<T: NetworkMessage>(&mut self, client_id: ClientId, message: &T) {
+        let channel_id = self.registry.channel_id::<T>();
+        self.server.broadcast_message_except(
+            client_id,
+            channel_id,
+            bincode::serialize(message).unwrap(),
+        );
+    }
+
+    /// Takes the next message of type `T` from a client. Registered messages are already drained
+    /// into [`FromClient`] events in `PreUpdate`, so systems normally read those instead.
+    pub fn receive<T: NetworkMessage>(
+        &mut self,
+        client_id: ClientId,
+    ) -> Option<Result<T, DecodeError>> {
+        let channel_id = self.registry.channel_id::<T>();
+        let message = self.server.receive_message(client_id, channel_id)?;
+        Some(decode(&message))
+    }
+}
+
+impl Deref for NetworkServer<'_> {
+    type Target = RenetServer;
+
+    fn deref(&self) -> &Self::Target {
+        &self.server
+    }
+}
+
+impl DerefMut for NetworkServer<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.server
+    }
+}
+
+/// [`RenetClient`] sending and receiving typed messages on their registered channels.
+#[derive(SystemParam)]
+pub struct NetworkClient<'w> {
+    client: ResMut<'w, RenetClient>,
+    registry: Res<'w, ChannelRegistry>,
+}
+
+impl NetworkClient<'_> {
+    pub fn send<T: NetworkMessage>(&mut self, message: &T) {
+        let channel_id = self.registry.channel_id::<T>();
+        self.client
+            .send_message(channel_id, bincode::serialize(message).unwrap());
+    }
+
+    /// Takes the next message of type `T` from the server. Registered messages are already
+    /// drained into [`FromServer`] events in `PreUpdate`, so systems normally read those instead.
+    pub fn receive<T: NetworkMessage>(&mut self) -> Option<Result<T, DecodeError>> {
+        let channel_id = self.registry.channel_id::<T>();
+        let message = self.client.receive_message(channel_id)?;
+        Some(decode(&message))
+    }
+}
+
+impl Deref for NetworkClient<'_> {
+    type Target = RenetClient;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+impl DerefMut for NetworkClient<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.client
+    }
+}
+
+fn receive_from_clients<T: NetworkMessage>(
+    mut server: NetworkServer,
+    mut messages: EventWriter<FromClient<T>>,
+    mut invalid_messages: EventWriter<InvalidMessage>,
+) {
+    for client_id in server.clients_id() {
+        while let Some(message) = server.receive::<T>(client_id) {
+            match message {
+                Ok(message) => messages.send(FromClient { client_id, message }),
+                Err(error) => invalid_messages.send(InvalidMessage { client_id, error }),
+            }
+        }
+    }
+}
+
+fn receive_from_server<T: NetworkMessage>(
+    mut client: NetworkClient,
+    mut messages: EventWriter<FromServer<T>>,
+) {
+    while let Some(message) = client.receive::<T>() {
+        match message {
+            Ok(message) => messages.send(FromServer { message }),
+            Err(err) => println!("Dropping {}: {}", type_name::<T>(), err),
+        }
+    }
+}
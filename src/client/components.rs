@@ -1 +1,112 @@
+use crate::{
+    combat::{GuardDirection, SwingDirection, WeaponKind},
+    projectile::{Flight, RangedWeaponKind},
+    PlayerSnapshot,
+};
+use bevy::prelude::*;
+use std::collections::VecDeque;
 
+/// Offset between where the camera was before a server correction and where it is now, decayed
+/// over a few frames so corrections do not snap the view.
+#[derive(Debug, Default, Component)]
+pub struct PredictionError(pub Vec3);
+
+/// Text counting down to the local player's respawn.
+#[derive(Debug, Component)]
+pub struct RespawnText;
+
+/// Pivot of a player's weapon model, at the hilt. A child of the camera for the local player and
+/// of the body for remote players.
+#[derive(Debug, Component)]
+pub struct WeaponModel;
+
+/// Swing being played on the weapon model of this entity.
+#[derive(Debug, Component)]
+pub struct SwingAnimation {
+    pub weapon: WeaponKind,
+    pub direction: SwingDirection,
+    pub elapsed: f32,
+}
+
+/// Guard held by the player owning the weapon model of this entity, shown between swings.
+#[derive(Debug, Component)]
+pub struct GuardPose(pub GuardDirection);
+
+/// Projectile in flight, simulated locally until the server announces its impact. Carries the
+/// [`ProjectileId`](crate::projectile::ProjectileId) it was spawned with.
+#[derive(Debug, Component)]
+pub struct ProjectileFlight {
+    pub kind: RangedWeaponKind,
+    pub flight: Flight,
+    /// Position before the last fixed step, rendered positions are interpolated from it.
+    pub previous: Vec3,
+}
+
+/// Projectile stuck in the map, removed when the timer finishes.
+#[derive(Debug, Component)]
+pub struct StuckProjectile(pub Timer);
+
+/// A player controlled by another client, rendered from interpolated snapshots.
+#[derive(Debug, Component)]
+pub struct RemotePlayer;
+
+/// Snapshots of a remote player, stamped with the local time they were received at.
+#[derive(Debug, Default, Component)]
+pub struct SnapshotBuffer {
+    pub snapshots: VecDeque<(f64, PlayerSnapshot)>,
+}
+
+impl SnapshotBuffer {
+    pub const CAPACITY: usize = 64;
+
+    pub fn push(&mut self, time: f64, snapshot: PlayerSnapshot) {
+        self.snapshots.push_back((time, snapshot));
+        while self.snapshots.len() > Self::CAPACITY {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// State at `time`, interpolated between the surrounding snapshots, or extrapolated from the
+    /// newest one for at most `max_extrapolation` seconds.
+    pub fn sample(&self, time: f64, max_extrapolation: f32) -> Option<PlayerSnapshot> {
+        let (oldest_time, oldest) = self.snapshots.front()?;
+        if time <= *oldest_time {
+            return Some(*oldest);
+        }
+
+        for ((from_time, from), (to_time, to)) in
+            self.snapshots.iter().zip(self.snapshots.iter().skip(1))
+        {
+            if time <= *to_time {
+                let t = ((time - from_time) / (to_time - from_time)) as f32;
+                return Some(PlayerSnapshot {
+                    position: from.position.lerp(to.position, t),
+                    yaw: lerp_angle(from.yaw, to.yaw, t),
+                    pitch: from.pitch + (to.pitch - from.pitch) * t,
+                    velocity: from.velocity.lerp(to.velocity, t),
+                    grounded: to.grounded,
+                });
+            }
+        }
+
+        let (newest_time, newest) = self.snapshots.back()?;
+        let elapsed = ((time - newest_time) as f32).min(max_extrapolation);
+        Some(PlayerSnapshot {
+            position: newest.position + newest.velocity * elapsed,
+            ..*newest
+        })
+    }
+
+    /// Drops snapshots that can no longer be sampled at or after `time`.
+    pub fn discard_before(&mut self, time: f64) {
+        while self.snapshots.len() > 2 && self.snapshots[1].0 <= time {
+            self.snapshots.pop_front();
+        }
+    }
+}
+
+fn lerp_angle(from: f32, to: f32, t: f32) -> f32 {
+    let delta =
+        (to - from + std::f32::consts::PI).rem_euclid(std::f32::consts::TAU) - std::f32::consts::PI;
+    from + delta * t
+}
@@ -0,0 +1,251 @@
+use crate::{
+    auth::{decode_token, read_token_file, AuthError},
+    combat::WeaponKind,
+    config::{read_config_file, ConfigError},
+    player_name_to_user_data, PROTOCOL_ID,
+};
+use bevy::prelude::*;
+use bevy_renet::renet::transport::{
+    generate_random_bytes, ClientAuthentication, NetcodeDisconnectReason, NetcodeError,
+};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::{fmt, io, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+
+/// Where the netcode client id comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ClientIdSource {
+    /// Milliseconds since the unix epoch at the moment of connecting.
+    #[default]
+    Time,
+    Random,
+    Fixed(u64),
+}
+
+impl ClientIdSource {
+    pub fn client_id(&self, current_time: Duration) -> u64 {
+        match self {
+            ClientIdSource::Time => current_time.as_millis() as u64,
+            ClientIdSource::Random => u64::from_le_bytes(generate_random_bytes()),
+            ClientIdSource::Fixed(client_id) => *client_id,
+        }
+    }
+}
+
+impl FromStr for ClientIdSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "time" => Ok(ClientIdSource::Time),
+            "random" => Ok(ClientIdSource::Random),
+            _ => s
+                .parse()
+                .map(ClientIdSource::Fixed)
+                .map_err(|_| format!("expected `time`, `random` or a number, got `{}`", s)),
+        }
+    }
+}
+
+/// How the client proves its identity to the server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClientAuthenticationConfig {
+    /// Connect with a self chosen client id. Only accepted by unsecure dev servers.
+    Unsecure,
+    /// Connect with a token read from a file written by the `token` binary.
+    TokenFile(PathBuf),
+    /// Connect with a hex encoded token.
+    Token(String),
+}
+
+impl ClientAuthenticationConfig {
+    pub fn client_authentication(
+        &self,
+        config: &ClientConfig,
+        current_time: Duration,
+    ) -> Result<ClientAuthentication, AuthError> {
+        match self {
+            ClientAuthenticationConfig::Unsecure => Ok(ClientAuthentication::Unsecure {
+                client_id: config.client_id.client_id(current_time),
+                protocol_id: config.protocol_id,
+                server_addr: config.server_addr,
+                user_data: Some(player_name_to_user_data(&config.player_name)),
+            }),
+            ClientAuthenticationConfig::TokenFile(path) => Ok(ClientAuthentication::Secure {
+                connect_token: read_token_file(path)?,
+            }),
+            ClientAuthenticationConfig::Token(blob) => Ok(ClientAuthentication::Secure {
+                connect_token: decode_token(blob)?,
+            }),
+        }
+    }
+}
+
+/// Why the client gave up connecting. Unlike timeouts these don't go away by retrying.
+#[derive(Debug)]
+pub enum ConnectError {
+    NoAuthentication,
+    Auth(AuthError),
+    Socket(io::Error),
+    /// The connect token is unusable, e.g. expired or meant for another protocol.
+    Token(NetcodeError),
+    /// The server turned down the connect token.
+    Refused(NetcodeDisconnectReason),
+    /// The map the server runs is not a plain asset path.
+    InvalidMap(String),
+    /// The server runs another map than the one this client already loaded.
+    MapChanged {
+        loaded: String,
+        map: String,
+    },
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::NoAuthentication => write!(f, "client authentication is not configured"),
+            ConnectError::Auth(err) => write!(f, "{}", err),
+            ConnectError::Socket(err) => write!(f, "could not open socket: {}", err),
+            ConnectError::Token(err) => write!(f, "unusable connect token: {}", err),
+            ConnectError::Refused(reason) => write!(f, "connection refused: {}", reason),
+            ConnectError::InvalidMap(map) => write!(f, "server runs invalid map `{}`", map),
+            ConnectError::MapChanged { loaded, map } => write!(
+                f,
+                "server runs map `{}` but `{}` is already loaded, restart to join it",
+                map, loaded
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+impl From<AuthError> for ConnectError {
+    fn from(err: AuthError) -> Self {
+        ConnectError::Auth(err)
+    }
+}
+
+/// Connect target of the client. Change it and enter `ClientStates::Connecting` to connect to
+/// another server.
+#[derive(Debug, Clone, Serialize, Deserialize, Resource)]
+#[serde(default)]
+pub struct ClientConfig {
+    pub server_addr: SocketAddr,
+    pub player_name: String,
+    pub client_id: ClientIdSource,
+    pub protocol_id: u64,
+    /// Has to be set explicitly, there is no implicit fallback to unsecure connections.
+    pub authentication: Option<ClientAuthenticationConfig>,
+    /// How far in the past remote players are rendered, in seconds.
+    pub interpolation_delay: f32,
+    /// How long remote players keep moving on their last velocity when snapshots stop arriving,
+    /// in seconds.
+    pub max_extrapolation: f32,
+    /// Melee weapon asked for when spawning. The server may hand out another one.
+    pub weapon: WeaponKind,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            server_addr: "127.0.0.1:5000".parse().unwrap(),
+            player_name: "Player".to_string(),
+            client_id: ClientIdSource::Time,
+            protocol_id: PROTOCOL_ID,
+            authentication: None,
+            interpolation_delay: 0.1,
+            max_extrapolation: 0.25,
+            weapon: WeaponKind::Sword,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(about = "Medieval Call of Duty client")]
+struct ClientArgs {
+    /// RON config file, overridden by any other argument or environment variable.
+    #[arg(long, env = "MCOD_CLIENT_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "MCOD_SERVER_ADDR")]
+    server_addr: Option<SocketAddr>,
+    #[arg(long, env = "MCOD_PLAYER_NAME")]
+    player_name: Option<String>,
+    /// `time`, `random` or a fixed number.
+    #[arg(long, env = "MCOD_CLIENT_ID")]
+    client_id: Option<ClientIdSource>,
+    #[arg(long, env = "MCOD_PROTOCOL_ID")]
+    protocol_id: Option<u64>,
+    /// Connect token file written by the `token` binary.
+    #[arg(long, env = "MCOD_TOKEN_FILE", conflicts_with_all = ["token", "unsecure"])]
+    token_file: Option<PathBuf>,
+    /// Hex encoded connect token.
+    #[arg(
+        long,
+        env = "MCOD_TOKEN",
+        conflicts_with = "unsecure",
+        hide_env_values = true
+    )]
+    token: Option<String>,
+    /// Connect without a token, only accepted by unsecure dev servers.
+    #[arg(long)]
+    unsecure: bool,
+    #[arg(long, env = "MCOD_INTERPOLATION_DELAY")]
+    interpolation_delay: Option<f32>,
+    #[arg(long, env = "MCOD_MAX_EXTRAPOLATION")]
+    max_extrapolation: Option<f32>,
+    #[arg(long, env = "MCOD_WEAPON")]
+    weapon: Option<WeaponKind>,
+}
+
+impl ClientConfig {
+    /// Builds the config from the command line, the environment and the optional config file,
+    /// in that order of precedence.
+    pub fn from_args() -> Result<Self, ConfigError> {
+        let args = ClientArgs::parse();
+
+        let mut config = match &args.config {
+            Some(path) => read_config_file(path)?,
+            None => ClientConfig::default(),
+        };
+
+        if let Some(server_addr) = args.server_addr {
+            config.server_addr = server_addr;
+        }
+        if let Some(player_name) = args.player_name {
+            config.player_name = player_name;
+        }
+        if let Some(client_id) = args.client_id {
+            config.client_id = client_id;
+        }
+        if let Some(protocol_id) = args.protocol_id {
+            config.protocol_id = protocol_id;
+        }
+        if let Some(path) = args.token_file {
+            config.authentication = Some(ClientAuthenticationConfig::TokenFile(path));
+        }
+        if let Some(blob) = args.token {
+            config.authentication = Some(ClientAuthenticationConfig::Token(blob));
+        }
+        if args.unsecure {
+            config.authentication = Some(ClientAuthenticationConfig::Unsecure);
+        }
+        if let Some(interpolation_delay) = args.interpolation_delay {
+            config.interpolation_delay = interpolation_delay;
+        }
+        if let Some(max_extrapolation) = args.max_extrapolation {
+            config.max_extrapolation = max_extrapolation;
+        }
+        if let Some(weapon) = args.weapon {
+            config.weapon = weapon;
+        }
+
+        if config.authentication.is_none() {
+            return Err(ConfigError::Invalid(
+                "no authentication given, pass --token-file, --token or --unsecure".to_string(),
+            ));
+        }
+
+        Ok(config)
+    }
+}
@@ -0,0 +1,9 @@
+use crate::controller::ControllerState;
+use bevy::prelude::*;
+
+/// Authoritative state of the local player received from the server.
+#[derive(Debug, Event)]
+pub struct LocalPlayerState {
+    pub sequence: u32,
+    pub state: ControllerState,
+}
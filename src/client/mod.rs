@@ -1,60 +1,55 @@
 mod components;
+mod config;
+mod events;
 mod resources;
 mod systems;
 
-use crate::{connection_config, PROTOCOL_ID};
+use crate::{
+    controller::fps_controller_render,
+    map::{MapPlugin, WorldAssets},
+    simulation::{SimulationPlugin, SimulationSet},
+    NetworkEntityMap, ProtocolPlugin,
+};
 use bevy::prelude::*;
 use bevy_asset_loader::prelude::*;
-use crate::controller::FpsControllerPlugin;
-use bevy_rapier3d::prelude::*;
+use bevy_rapier3d::prelude::PhysicsSet;
 use bevy_renet::{
-    renet::{
-        transport::{ClientAuthentication, NetcodeClientTransport},
-        RenetClient,
-    },
-    transport::NetcodeClientPlugin,
-    RenetClientPlugin,
+    renet::transport::NetcodeClientTransport, transport::NetcodeClientPlugin, RenetClientPlugin,
 };
+pub use config::{ClientAuthenticationConfig, ClientConfig, ClientIdSource, ConnectError};
+use events::*;
 use resources::*;
-use std::{net::UdpSocket, time::SystemTime};
 use systems::*;
 
 #[derive(Clone, Eq, PartialEq, Debug, Hash, Default, States)]
-enum ClientStates {
+pub enum ClientStates {
     #[default]
-    AssetLoading,
+    Connecting,
+    /// Connected, waiting for the server to answer our hello.
+    Handshake,
+    /// Loading the map the server runs.
+    LoadingMap,
     Playing,
+    Rejected,
+    /// Connecting failed in a way retrying would not fix.
+    ConnectionFailed,
 }
 
-// TODO: Parameterize this with: ip, etc.
-pub struct ClientPlugin;
+pub struct ClientPlugin {
+    pub config: ClientConfig,
+}
 
 impl Plugin for ClientPlugin {
     fn build(&self, app: &mut App) {
-        let server_addr = "127.0.0.1:5000".parse().unwrap();
-        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
-        let current_time = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap();
-        let client_id = current_time.as_millis() as u64;
-        let authentication = ClientAuthentication::Unsecure {
-            client_id,
-            protocol_id: PROTOCOL_ID,
-            server_addr,
-            user_data: None,
-        };
-
-        let transport = NetcodeClientTransport::new(current_time, authentication, socket).unwrap();
-        let client = RenetClient::new(connection_config());
-
         app.add_plugins(DefaultPlugins)
             .add_plugins(RenetClientPlugin)
             .add_plugins(NetcodeClientPlugin)
-            .add_plugins(RapierPhysicsPlugin::<NoUserData>::default())
-            .add_plugins(FpsControllerPlugin)
+            .add_plugins(ProtocolPlugin)
+            .add_plugins(SimulationPlugin::default())
+            .add_plugins(MapPlugin::default())
             .add_state::<ClientStates>()
             .add_loading_state(
-                LoadingState::new(ClientStates::AssetLoading)
+                LoadingState::new(ClientStates::LoadingMap)
                     .continue_to_state(ClientStates::Playing)
                     .load_collection::<WorldAssets>(),
             )
@@ -62,13 +57,76 @@ impl Plugin for ClientPlugin {
                 color: Color::WHITE,
                 brightness: 0.5,
             })
-            .insert_resource(client)
-            .insert_resource(transport)
-            .insert_resource(RapierConfiguration::default())
-            .add_systems(OnEnter(ClientStates::Playing), (setup, initial_spawn))
+            .insert_resource(self.config.clone())
+            .init_resource::<InputSequence>()
+            .init_resource::<PredictionHistory>()
+            .init_resource::<ReceivedSnapshots>()
+            .init_resource::<NetworkEntityMap>()
+            .add_event::<LocalPlayerState>()
+            .add_systems(Update, spawn_map_scene)
+            .add_systems(OnEnter(ClientStates::Connecting), connect)
+            .add_systems(
+                Update,
+                wait_for_connection
+                    .run_if(in_state(ClientStates::Connecting))
+                    .run_if(resource_exists::<NetcodeClientTransport>()),
+            )
+            .add_systems(
+                Update,
+                (handle_handshake, handle_disconnect)
+                    .chain()
+                    .run_if(in_state(ClientStates::Handshake)),
+            )
+            .add_systems(
+                Update,
+                handle_disconnect.run_if(in_state(ClientStates::LoadingMap)),
+            )
+            .add_systems(OnEnter(ClientStates::Rejected), show_rejection)
+            .add_systems(
+                OnEnter(ClientStates::ConnectionFailed),
+                show_connection_failure,
+            )
+            .add_systems(OnEnter(ClientStates::Playing), initial_spawn)
             .add_systems(
                 Update,
-                (handle_server_messages).run_if(in_state(ClientStates::Playing)),
-            );
+                (
+                    handle_server_messages,
+                    handle_respawns,
+                    update_respawn_countdown,
+                    handle_combat_messages,
+                    equip_weapon_models,
+                    animate_weapons,
+                    send_attack,
+                    send_guard,
+                    send_ranged,
+                    handle_projectile_messages,
+                    render_projectiles,
+                    handle_snapshots,
+                    reconcile_local_player,
+                    interpolate_remote_players,
+                    handle_disconnect,
+                )
+                    .chain()
+                    .run_if(in_state(ClientStates::Playing)),
+            )
+            .add_systems(
+                FixedUpdate,
+                simulate_projectiles
+                    .after(PhysicsSet::Writeback)
+                    .run_if(in_state(ClientStates::Playing)),
+            )
+            .add_systems(
+                FixedUpdate,
+                send_input
+                    .in_set(SimulationSet::Input)
+                    .run_if(in_state(ClientStates::Playing)),
+            )
+            .add_systems(
+                PostUpdate,
+                smooth_prediction_error
+                    .after(fps_controller_render)
+                    .run_if(in_state(ClientStates::Playing)),
+            )
+            .add_systems(OnExit(ClientStates::Playing), despawn_players);
     }
 }
@@ -1,9 +1,96 @@
-use bevy::{gltf::*, prelude::*};
-use bevy_asset_loader::prelude::*;
-use bevy_renet::renet::ClientId;
-
-#[derive(AssetCollection, Resource)]
-pub struct WorldAssets {
-    #[asset(path = "playground.glb")]
-    pub playground: Handle<Gltf>,
+use super::ConnectError;
+use crate::{
+    controller::FpsControllerInput, is_newer_sequence, snapshot::WorldSnapshot, NetworkEntity,
+    RejectReason,
+};
+use bevy::prelude::*;
+use std::collections::VecDeque;
+
+/// Why connecting failed for good.
+#[derive(Debug, Resource)]
+pub struct ConnectionFailure(pub ConnectError);
+
+/// Asset path of the map loaded for the server that welcomed this client.
+#[derive(Debug, Resource)]
+pub struct LoadedMap(pub String);
+
+/// Why the server turned this client away during the handshake.
+#[derive(Debug, Resource)]
+pub struct Rejection(pub RejectReason);
+
+/// Network id of the player controlled by this client.
+#[derive(Debug, Resource)]
+pub struct LocalPlayer {
+    pub entity: NetworkEntity,
+}
+
+/// Time left until the dead local player respawns.
+#[derive(Debug, Resource)]
+pub struct RespawnCountdown(pub Timer);
+
+/// Recently decoded snapshots, used as baselines for the delta-encoded ones that follow.
+#[derive(Debug, Default, Resource)]
+pub struct ReceivedSnapshots {
+    pub snapshots: VecDeque<(u64, WorldSnapshot)>,
+}
+
+impl ReceivedSnapshots {
+    pub const CAPACITY: usize = 32;
+
+    pub fn push(&mut self, tick: u64, snapshot: WorldSnapshot) {
+        self.snapshots.push_back((tick, snapshot));
+        while self.snapshots.len() > Self::CAPACITY {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn latest_tick(&self) -> Option<u64> {
+        self.snapshots.back().map(|(tick, _)| *tick)
+    }
+
+    pub fn get(&self, tick: u64) -> Option<&WorldSnapshot> {
+        self.snapshots
+            .iter()
+            .find(|(received, _)| *received == tick)
+            .map(|(_, snapshot)| snapshot)
+    }
+}
+
+/// Sequence number of the last input sent to the server.
+#[derive(Debug, Default, Resource)]
+pub struct InputSequence(pub u32);
+
+#[derive(Debug, Clone, Copy)]
+pub struct PredictedInput {
+    pub sequence: u32,
+    pub input: FpsControllerInput,
+    pub dt: f32,
+}
+
+/// Inputs applied locally but not yet acknowledged by the server, replayed on correction.
+#[derive(Debug, Default, Resource)]
+pub struct PredictionHistory {
+    pub inputs: VecDeque<PredictedInput>,
+}
+
+impl PredictionHistory {
+    pub const CAPACITY: usize = 128;
+
+    pub fn push(&mut self, input: PredictedInput) {
+        self.inputs.push_back(input);
+        while self.inputs.len() > Self::CAPACITY {
+            self.inputs.pop_front();
+        }
+    }
+
+    /// Drops every input up to and including `sequence`.
+    pub fn acknowledge(&mut self, sequence: u32) {
+        while self
+            .inputs
+            .front()
+            .is_some_and(|input| !is_newer_sequence(input.sequence, sequence))
+        {
+            self.inputs.pop_front();
+        }
+    }
 }
@@ -1,54 +1,340 @@
-use std::f32::consts::TAU;
-use super::resources::*;
-use crate::{ClientChannel, ClientMessage, ServerChannel, ServerMessage, controller::*};
-use bevy::{gltf::*, prelude::*};
+use super::{components::*, events::*, resources::*, ClientConfig, ClientStates, ConnectError};
+use crate::{
+    channel::{ChannelRegistry, FromServer, NetworkClient},
+    combat::{
+        Defense, GuardDirection, SwingDirection, SwingPhase, WeaponKind, SWING_ORIGIN_HEIGHT,
+    },
+    controller::*,
+    map::{register_map, MapLoaded, MapScene, MapSettings, WorldAssets},
+    projectile::{Flight, ProjectileId, RangedWeaponKind, GRAVITY},
+    simulation::set_tick_rate,
+    snapshot::WorldSnapshot,
+    ClientMessage, NetworkEntity, NetworkEntityMap, PlayerInfo, PlayerInput, PlayerSnapshot,
+    ProjectileInfo, ServerMessage, Snapshot, SnapshotAck, PROTOCOL_VERSION,
+};
+use bevy::prelude::*;
+use bevy_asset_loader::prelude::*;
 use bevy_rapier3d::prelude::*;
-use bevy_renet::renet::RenetClient;
+use bevy_renet::renet::{
+    transport::{NetcodeClientTransport, NetcodeDisconnectReason},
+    RenetClient,
+};
+use std::{
+    f32::consts::TAU,
+    net::UdpSocket,
+    path::{self, Path},
+    time::SystemTime,
+};
 
-pub fn setup(
+pub fn spawn_map_scene(mut commands: Commands, mut loaded: EventReader<MapLoaded>) {
+    for map in loaded.read() {
+        commands.spawn((
+            SceneBundle {
+                scene: map.scene.clone(),
+                ..default()
+            },
+            MapScene,
+        ));
+    }
+}
+
+pub fn connect(
     mut commands: Commands,
-    world: ResMut<WorldAssets>,
-    gltf_assets: Res<Assets<Gltf>>,
-    gltf_mesh_assets: Res<Assets<GltfMesh>>,
-    gltf_node_assets: Res<Assets<GltfNode>>,
-    mesh_assets: Res<Assets<Mesh>>,
-) {
-    if let Some(gltf) = gltf_assets.get(&world.playground) {
-        let scene = gltf.scenes.first().unwrap().clone();
-
-        commands.spawn(SceneBundle { scene, ..default() });
-
-        for node in &gltf.nodes {
-            let node = gltf_node_assets.get(node).unwrap();
-            if let Some(gltf_mesh) = node.mesh.clone() {
-                let gltf_mesh = gltf_mesh_assets.get(&gltf_mesh).unwrap();
-                for mesh_primitive in &gltf_mesh.primitives {
-                    let mesh = mesh_assets.get(&mesh_primitive.mesh).unwrap();
-                    commands.spawn((
-                        Collider::from_bevy_mesh(mesh, &ComputedColliderShape::TriMesh).unwrap(),
-                        RigidBody::Fixed,
-                        TransformBundle::from_transform(node.transform),
-                    ));
+    config: Res<ClientConfig>,
+    registry: Res<ChannelRegistry>,
+    mut next_state: ResMut<NextState<ClientStates>>,
+) {
+    match new_connection(&config, &registry) {
+        Ok((client, transport)) => {
+            commands.insert_resource(client);
+            commands.insert_resource(transport);
+        }
+        Err(err) => {
+            commands.insert_resource(ConnectionFailure(err));
+            next_state.set(ClientStates::ConnectionFailed);
+        }
+    }
+}
+
+fn new_connection(
+    config: &ClientConfig,
+    registry: &ChannelRegistry,
+) -> Result<(RenetClient, NetcodeClientTransport), ConnectError> {
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(ConnectError::Socket)?;
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let authentication = config
+        .authentication
+        .as_ref()
+        .ok_or(ConnectError::NoAuthentication)?
+        .client_authentication(config, current_time)?;
+
+    let transport = NetcodeClientTransport::new(current_time, authentication, socket)
+        .map_err(ConnectError::Token)?;
+    let client = RenetClient::new(registry.connection_config());
+
+    println!(
+        "Connecting as {} ({})",
+        config.player_name,
+        transport.client_id()
+    );
+
+    Ok((client, transport))
+}
+
+pub fn wait_for_connection(
+    mut commands: Commands,
+    config: Res<ClientConfig>,
+    registry: Res<ChannelRegistry>,
+    mut client: NetworkClient,
+    mut transport: ResMut<NetcodeClientTransport>,
+    mut next_state: ResMut<NextState<ClientStates>>,
+) {
+    if client.is_connected() {
+        println!("Connected to server.");
+
+        client.send(&ClientMessage::Hello {
+            version: PROTOCOL_VERSION,
+            name: config.player_name.clone(),
+        });
+        next_state.set(ClientStates::Handshake);
+    } else if client.is_disconnected() {
+        println!("Could not connect: {:?}", client.disconnect_reason());
+
+        // A refused token is refused again, only timeouts are worth retrying.
+        let connection = match transport.disconnect_reason() {
+            Some(
+                reason @ (NetcodeDisconnectReason::ConnectTokenExpired
+                | NetcodeDisconnectReason::ConnectionDenied),
+            ) => Err(ConnectError::Refused(reason)),
+            _ => new_connection(&config, &registry),
+        };
+        match connection {
+            Ok(connection) => (*client, *transport) = connection,
+            Err(err) => {
+                commands.insert_resource(ConnectionFailure(err));
+                next_state.set(ClientStates::ConnectionFailed);
+            }
+        }
+    }
+}
+
+pub fn handle_handshake(
+    mut commands: Commands,
+    mut messages: EventReader<FromServer<ServerMessage>>,
+    loaded_map: Option<Res<LoadedMap>>,
+    world_assets: Option<Res<WorldAssets>>,
+    mut dynamic_assets: ResMut<DynamicAssets>,
+    mut next_state: ResMut<NextState<ClientStates>>,
+) {
+    for FromServer { message } in messages.read() {
+        match message {
+            ServerMessage::Welcome { info } => {
+                println!(
+                    "Joined server running {} at {} ticks per second",
+                    info.map, info.tick_rate
+                );
+
+                // The map is shared with the server's simulation, so prediction only agrees with
+                // it on the same map. Loaded maps are never unloaded.
+                let map_error = match loaded_map.as_deref() {
+                    Some(LoadedMap(loaded)) if *loaded != info.map => {
+                        Some(ConnectError::MapChanged {
+                            loaded: loaded.clone(),
+                            map: info.map.clone(),
+                        })
+                    }
+                    Some(_) => None,
+                    None if !is_asset_path(&info.map) => {
+                        Some(ConnectError::InvalidMap(info.map.clone()))
+                    }
+                    None => {
+                        register_map(&mut dynamic_assets, &info.map);
+                        commands.insert_resource(LoadedMap(info.map.clone()));
+                        None
+                    }
+                };
+                if let Some(err) = map_error {
+                    commands.insert_resource(ConnectionFailure(err));
+                    next_state.set(ClientStates::ConnectionFailed);
+                    return;
                 }
+
+                let tick_rate = info.tick_rate;
+                commands.add(move |world: &mut World| set_tick_rate(world, tick_rate));
+                commands.insert_resource(info.clone());
+                next_state.set(if world_assets.is_some() {
+                    ClientStates::Playing
+                } else {
+                    ClientStates::LoadingMap
+                });
+                return;
             }
+            ServerMessage::Rejected { reason } => {
+                println!("Rejected by server: {}", reason);
+
+                commands.insert_resource(Rejection(reason.clone()));
+                next_state.set(ClientStates::Rejected);
+                return;
+            }
+            // Anything sent before the welcome is repeated by the world state after spawning.
+            _ => {}
         }
     }
 }
 
-pub fn initial_spawn(mut client: ResMut<RenetClient>) {
-    let message = bincode::serialize(&ClientMessage::SpawnMe).unwrap();
-    client.send_message(ClientChannel::ClientMessage, message);
+/// Whether a path sent by the server stays inside the asset folder.
+fn is_asset_path(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, path::Component::Normal(_)))
+}
+
+pub fn show_rejection(
+    mut commands: Commands,
+    rejection: Res<Rejection>,
+    mut transport: ResMut<NetcodeClientTransport>,
+) {
+    transport.disconnect();
+    commands.remove_resource::<RenetClient>();
+    commands.remove_resource::<NetcodeClientTransport>();
+
+    spawn_status_text(
+        &mut commands,
+        format!("Rejected by server: {}", rejection.0),
+    );
+}
+
+pub fn show_connection_failure(
+    mut commands: Commands,
+    failure: Res<ConnectionFailure>,
+    transport: Option<ResMut<NetcodeClientTransport>>,
+) {
+    println!("Could not connect: {}", failure.0);
+
+    if let Some(mut transport) = transport {
+        transport.disconnect();
+    }
+    commands.remove_resource::<RenetClient>();
+    commands.remove_resource::<NetcodeClientTransport>();
+
+    spawn_status_text(&mut commands, format!("Could not connect: {}", failure.0));
+}
+
+/// Full screen message shown when the client cannot play.
+fn spawn_status_text(commands: &mut Commands, text: String) {
+    commands.spawn(Camera2dBundle::default());
+    commands.spawn(
+        TextBundle::from_section(
+            text,
+            TextStyle {
+                font_size: 32.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            margin: UiRect::all(Val::Auto),
+            ..default()
+        }),
+    );
+}
+
+pub fn handle_disconnect(
+    mut commands: Commands,
+    client: Res<RenetClient>,
+    mut next_state: ResMut<NextState<ClientStates>>,
+) {
+    if client.is_disconnected() {
+        println!("Disconnected from server: {:?}", client.disconnect_reason());
+
+        commands.remove_resource::<RenetClient>();
+        commands.remove_resource::<NetcodeClientTransport>();
+        next_state.set(ClientStates::Connecting);
+    }
+}
+
+pub fn despawn_players(
+    mut commands: Commands,
+    mut history: ResMut<PredictionHistory>,
+    mut received: ResMut<ReceivedSnapshots>,
+    mut entity_map: ResMut<NetworkEntityMap>,
+    query: Query<
+        Entity,
+        Or<(
+            With<LogicalPlayer>,
+            With<RenderPlayer>,
+            With<RemotePlayer>,
+            With<RespawnText>,
+            With<ProjectileId>,
+        )>,
+    >,
+) {
+    history.inputs.clear();
+    received.snapshots.clear();
+    entity_map.clear();
+    commands.remove_resource::<LocalPlayer>();
+    commands.remove_resource::<RespawnCountdown>();
+
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+pub fn initial_spawn(mut client: NetworkClient, config: Res<ClientConfig>) {
+    client.send(&ClientMessage::SpawnMe {
+        weapon: config.weapon,
+    });
+}
+
+pub fn send_input(
+    time: Res<Time>,
+    mut client: NetworkClient,
+    mut sequence: ResMut<InputSequence>,
+    mut history: ResMut<PredictionHistory>,
+    query: Query<&FpsControllerInput, With<LogicalPlayer>>,
+) {
+    if let Ok(input) = query.get_single() {
+        sequence.0 = sequence.0.wrapping_add(1);
+        history.push(PredictedInput {
+            sequence: sequence.0,
+            input: *input,
+            dt: time.delta_seconds(),
+        });
+
+        client.send(&PlayerInput {
+            sequence: sequence.0,
+            input: *input,
+        });
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn handle_server_messages(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    mut client: ResMut<RenetClient>,
+    mut messages: EventReader<FromServer<ServerMessage>>,
+    mut entity_map: ResMut<NetworkEntityMap>,
+    local_player: Option<Res<LocalPlayer>>,
+    names: Query<&Name>,
+    local_entities: Query<Entity, Or<(With<LogicalPlayer>, With<RenderPlayer>)>>,
+    mut remote_players: Query<&mut Visibility, With<RemotePlayer>>,
+    mut next_state: ResMut<NextState<ClientStates>>,
 ) {
-    while let Some(message) = client.receive_message(ServerChannel::ServerMessage) {
-        let server_message = bincode::deserialize(&message).unwrap();
-        match server_message {
+    for FromServer { message } in messages.read() {
+        match message.clone() {
+            ServerMessage::Welcome { .. } => {}
+            // Sent before the server drops a misbehaving client.
+            ServerMessage::Rejected { reason } => {
+                println!("Rejected by server: {}", reason);
+
+                commands.insert_resource(Rejection(reason));
+                next_state.set(ClientStates::Rejected);
+                return;
+            }
             ServerMessage::PlayerConnected { id } => {
                 println!("Player {} connected.", id);
             }
@@ -56,30 +342,869 @@ pub fn handle_server_messages(
                 println!("Player {} disconnected.", id);
             }
             ServerMessage::SpawnPlayer {
-                server_entity,
+                entity,
+                name,
                 position,
             } => {
-                println!("Spawning player at {:?}", position);
+                if entity_map.local(entity).is_some() {
+                    continue;
+                }
+                println!("Spawning player {} at {:?}", name, position);
+
+                let info = PlayerInfo {
+                    entity,
+                    name,
+                    position,
+                    dead: false,
+                };
+                let local_entity =
+                    spawn_remote_player(&mut commands, &mut meshes, &mut materials, info);
+                entity_map.insert(entity, local_entity);
             }
-            ServerMessage::SpawnHim {
-                server_entity,
-                position,
-            } => {
+            ServerMessage::WorldState { players, .. } => {
+                println!("Received world state with {} players", players.len());
+
+                for info in players {
+                    let entity = info.entity;
+                    if entity_map.local(entity).is_some() {
+                        continue;
+                    }
+
+                    let local_entity =
+                        spawn_remote_player(&mut commands, &mut meshes, &mut materials, info);
+                    entity_map.insert(entity, local_entity);
+                }
+            }
+            ServerMessage::SpawnHim { entity, position } => {
                 println!("Spawning him at {:?}", position);
 
-                let logical_entity = commands.spawn(FpsCharacterController::default()).id();
+                spawn_local_player(
+                    &mut commands,
+                    &mut entity_map,
+                    &local_entities,
+                    entity,
+                    position,
+                );
+            }
+            ServerMessage::Despawn { entity } => {
+                println!("Despawning {:?}", entity);
+
+                if let Some(local_entity) = entity_map.remove_network(entity) {
+                    commands.entity(local_entity).despawn_recursive();
+                }
+            }
+            ServerMessage::PlayerRespawned { .. }
+            | ServerMessage::Swing { .. }
+            | ServerMessage::Guard { .. }
+            | ServerMessage::Feint { .. }
+            | ServerMessage::Clash { .. }
+            | ServerMessage::ProjectileSpawned { .. }
+            | ServerMessage::ProjectileImpact { .. } => {}
+            ServerMessage::PlayerDied {
+                victim,
+                killer,
+                cause,
+                ..
+            } => {
+                let name_of = |entity: NetworkEntity| {
+                    if local_player
+                        .as_ref()
+                        .is_some_and(|local| local.entity == entity)
+                    {
+                        return "You".to_string();
+                    }
+                    entity_map
+                        .local(entity)
+                        .and_then(|entity| names.get(entity).ok())
+                        .map_or_else(|| format!("{:?}", entity), |name| name.to_string())
+                };
+                match killer {
+                    Some(killer) => {
+                        println!("{} killed {} ({})", name_of(killer), name_of(victim), cause)
+                    }
+                    None => println!("{} died ({})", name_of(victim), cause),
+                }
+
+                if let Some(mut visibility) = entity_map
+                    .local(victim)
+                    .and_then(|entity| remote_players.get_mut(entity).ok())
+                {
+                    *visibility = Visibility::Hidden;
+                }
+            }
+        }
+    }
+}
+
+/// Spawns the local player's logical entity and camera, replacing the previous ones.
+fn spawn_local_player(
+    commands: &mut Commands,
+    entity_map: &mut NetworkEntityMap,
+    previous: &Query<Entity, Or<(With<LogicalPlayer>, With<RenderPlayer>)>>,
+    entity: NetworkEntity,
+    position: Vec3,
+) {
+    for previous in previous.iter() {
+        commands.entity(previous).despawn_recursive();
+    }
+    entity_map.remove_network(entity);
 
+    commands.insert_resource(LocalPlayer { entity });
+
+    let logical_entity = commands
+        .spawn((
+            FpsCharacterController::default().with_translation(position),
+            entity,
+        ))
+        .id();
+    entity_map.insert(entity, logical_entity);
+
+    commands.spawn((
+        Camera3dBundle {
+            projection: Projection::Perspective(PerspectiveProjection {
+                fov: TAU / 5.0,
+                ..default()
+            }),
+            ..default()
+        },
+        RenderPlayer { logical_entity },
+        PredictionError::default(),
+    ));
+}
+
+/// Counts down to the local player's respawn and puts respawned players back into the world.
+#[allow(clippy::too_many_arguments)]
+pub fn handle_respawns(
+    mut commands: Commands,
+    mut messages: EventReader<FromServer<ServerMessage>>,
+    mut entity_map: ResMut<NetworkEntityMap>,
+    mut history: ResMut<PredictionHistory>,
+    local_player: Option<Res<LocalPlayer>>,
+    local_entities: Query<Entity, Or<(With<LogicalPlayer>, With<RenderPlayer>)>>,
+    countdown_texts: Query<Entity, With<RespawnText>>,
+    mut remote_players: Query<
+        (&mut Transform, &mut Visibility, &mut SnapshotBuffer),
+        With<RemotePlayer>,
+    >,
+) {
+    let is_local = |entity| {
+        local_player
+            .as_ref()
+            .is_some_and(|local| local.entity == entity)
+    };
+
+    for FromServer { message } in messages.read() {
+        match message.clone() {
+            ServerMessage::PlayerDied {
+                victim, respawn_in, ..
+            } if is_local(victim) => {
+                commands.insert_resource(RespawnCountdown(Timer::from_seconds(
+                    respawn_in,
+                    TimerMode::Once,
+                )));
                 commands.spawn((
-                    Camera3dBundle {
-                        projection: Projection::Perspective(PerspectiveProjection {
-                            fov: TAU / 5.0,
+                    TextBundle::from_section(
+                        "",
+                        TextStyle {
+                            font_size: 32.0,
+                            color: Color::WHITE,
                             ..default()
-                        }),
+                        },
+                    )
+                    .with_style(Style {
+                        margin: UiRect::all(Val::Auto),
                         ..default()
-                    },
-                    RenderPlayer { logical_entity },
+                    }),
+                    RespawnText,
                 ));
             }
+            ServerMessage::PlayerRespawned { entity, position } if is_local(entity) => {
+                commands.remove_resource::<RespawnCountdown>();
+                for text in countdown_texts.iter() {
+                    commands.entity(text).despawn_recursive();
+                }
+                // Inputs predicted before the respawn would replay from the wrong position.
+                history.inputs.clear();
+
+                spawn_local_player(
+                    &mut commands,
+                    &mut entity_map,
+                    &local_entities,
+                    entity,
+                    position,
+                );
+            }
+            ServerMessage::PlayerRespawned { entity, position } => {
+                if let Some((mut transform, mut visibility, mut buffer)) = entity_map
+                    .local(entity)
+                    .and_then(|entity| remote_players.get_mut(entity).ok())
+                {
+                    transform.translation = position;
+                    *visibility = Visibility::Inherited;
+                    // Interpolating from the snapshots before death would drag the body across
+                    // the map.
+                    buffer.snapshots.clear();
+                }
+            }
+            _ => {}
         }
     }
 }
+
+pub fn update_respawn_countdown(
+    time: Res<Time>,
+    countdown: Option<ResMut<RespawnCountdown>>,
+    mut texts: Query<&mut Text, With<RespawnText>>,
+) {
+    let Some(mut countdown) = countdown else {
+        return;
+    };
+    countdown.0.tick(time.delta());
+
+    let seconds = countdown.0.remaining_secs().ceil();
+    for mut text in texts.iter_mut() {
+        text.sections[0].value = format!("Respawning in {}", seconds);
+    }
+}
+
+/// Gives every newly spawned player a weapon model.
+pub fn equip_weapon_models(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    cameras: Query<Entity, Added<RenderPlayer>>,
+    remote_players: Query<Entity, Added<RemotePlayer>>,
+) {
+    let reach = WeaponKind::default().stats().reach;
+    let holders = cameras
+        .iter()
+        .map(|camera| (camera, Vec3::new(0.35, -0.3, -0.2)))
+        .chain(
+            remote_players
+                .iter()
+                .map(|player| (player, Vec3::new(0.35, SWING_ORIGIN_HEIGHT, 0.0))),
+        );
+
+    for (holder, pivot) in holders {
+        let model = commands
+            .spawn((
+                SpatialBundle::from_transform(
+                    Transform::from_translation(pivot).with_rotation(rest_rotation()),
+                ),
+                WeaponModel,
+            ))
+            .with_children(|parent| {
+                parent.spawn(PbrBundle {
+                    mesh: meshes.add(shape::Box::new(0.05, 0.05, reach).into()),
+                    material: materials.add(Color::rgb(0.7, 0.7, 0.75).into()),
+                    transform: Transform::from_translation(Vec3::NEG_Z * reach / 2.0),
+                    ..default()
+                });
+            })
+            .id();
+        commands.entity(holder).add_child(model);
+    }
+}
+
+pub fn send_attack(
+    mut client: NetworkClient,
+    buttons: Res<Input<MouseButton>>,
+    sequence: Res<InputSequence>,
+    countdown: Option<Res<RespawnCountdown>>,
+    players: Query<&MouseSwipe, With<LogicalPlayer>>,
+) {
+    let Ok(swipe) = players.get_single() else {
+        return;
+    };
+    if !buttons.just_pressed(MouseButton::Left) || countdown.is_some() {
+        return;
+    }
+
+    // The direction of the swing follows the mouse movement right before the click.
+    let direction = SwingDirection::from_swipe(swipe.0).unwrap_or(SwingDirection::Thrust);
+    client.send(&ClientMessage::Attack {
+        direction,
+        sequence: sequence.0,
+    });
+}
+
+/// Raises the guard while the right mouse button is held, turning it to follow the mouse, and
+/// feints with the feint key.
+pub fn send_guard(
+    mut client: NetworkClient,
+    buttons: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    countdown: Option<Res<RespawnCountdown>>,
+    players: Query<&MouseSwipe, With<LogicalPlayer>>,
+    mut raised: Local<Option<GuardDirection>>,
+) {
+    let Ok(swipe) = players.get_single() else {
+        *raised = None;
+        return;
+    };
+    if countdown.is_some() {
+        // The server lowers the guards of dead players.
+        *raised = None;
+        return;
+    }
+
+    if keys.just_pressed(KeyCode::Q) {
+        client.send(&ClientMessage::Feint);
+    }
+
+    if buttons.pressed(MouseButton::Right) {
+        let direction = GuardDirection::from_swipe(swipe.0)
+            .or(*raised)
+            .unwrap_or(GuardDirection::Up);
+        if buttons.just_pressed(MouseButton::Right) || *raised != Some(direction) {
+            client.send(&ClientMessage::Guard {
+                direction: Some(direction),
+            });
+            *raised = Some(direction);
+        }
+    } else if raised.take().is_some() {
+        client.send(&ClientMessage::Guard { direction: None });
+    }
+}
+
+/// Plays the swings, guards, feints and clashes announced by the server on weapon models.
+pub fn handle_combat_messages(
+    mut commands: Commands,
+    mut messages: EventReader<FromServer<ServerMessage>>,
+    entity_map: Res<NetworkEntityMap>,
+    local_player: Option<Res<LocalPlayer>>,
+    cameras: Query<Entity, With<RenderPlayer>>,
+    names: Query<&Name>,
+) {
+    let is_local = |entity: NetworkEntity| {
+        local_player
+            .as_ref()
+            .is_some_and(|local| local.entity == entity)
+    };
+    let holder_of = |entity: NetworkEntity| {
+        if is_local(entity) {
+            cameras.get_single().ok()
+        } else {
+            entity_map.local(entity)
+        }
+    };
+    let name_of = |entity: NetworkEntity| {
+        entity_map
+            .local(entity)
+            .and_then(|entity| names.get(entity).ok())
+            .map_or_else(|| format!("{:?}", entity), |name| name.to_string())
+    };
+
+    for FromServer { message } in messages.read() {
+        match *message {
+            ServerMessage::Swing {
+                attacker,
+                weapon,
+                direction,
+            } => {
+                if let Some(holder) = holder_of(attacker) {
+                    // Attacking lowers the guard.
+                    commands
+                        .entity(holder)
+                        .remove::<GuardPose>()
+                        .insert(SwingAnimation {
+                            weapon,
+                            direction,
+                            elapsed: 0.0,
+                        });
+                }
+            }
+            ServerMessage::Guard { entity, direction } => {
+                let Some(holder) = holder_of(entity) else {
+                    continue;
+                };
+                match direction {
+                    Some(direction) => commands.entity(holder).insert(GuardPose(direction)),
+                    None => commands.entity(holder).remove::<GuardPose>(),
+                };
+            }
+            ServerMessage::Feint { attacker } => {
+                if let Some(holder) = holder_of(attacker) {
+                    commands.entity(holder).remove::<SwingAnimation>();
+                }
+            }
+            ServerMessage::Clash {
+                attacker,
+                defender,
+                defense,
+            } => {
+                if let Some(holder) = holder_of(attacker) {
+                    commands.entity(holder).remove::<SwingAnimation>();
+                }
+
+                if is_local(defender) {
+                    println!("You {} {}'s attack", defense, name_of(attacker));
+                } else if is_local(attacker) {
+                    println!("{} {} your attack", name_of(defender), defense);
+                    if defense == Defense::Parried {
+                        println!("You are staggered");
+                    }
+                }
+            }
+            ServerMessage::PlayerRespawned { entity, .. } => {
+                if let Some(holder) = holder_of(entity) {
+                    commands
+                        .entity(holder)
+                        .remove::<(SwingAnimation, GuardPose)>();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Poses weapon models for the swing in progress, the guard held or at rest.
+pub fn animate_weapons(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut swings: Query<(Entity, &mut SwingAnimation)>,
+    guards: Query<&GuardPose>,
+    mut models: Query<(&Parent, &mut Transform), With<WeaponModel>>,
+) {
+    for (holder, mut animation) in swings.iter_mut() {
+        animation.elapsed += time.delta_seconds();
+        if animation
+            .weapon
+            .stats()
+            .phase_at(animation.elapsed)
+            .is_none()
+        {
+            commands.entity(holder).remove::<SwingAnimation>();
+        }
+    }
+
+    for (parent, mut transform) in models.iter_mut() {
+        let holder = parent.get();
+        let idle = guards
+            .get(holder)
+            .map_or_else(|_| rest_rotation(), |guard| guard_rotation(guard.0));
+        let Ok((_, animation)) = swings.get(holder) else {
+            transform.rotation = idle;
+            continue;
+        };
+
+        let stats = animation.weapon.stats();
+        let aim = |progress| {
+            Quat::from_rotation_arc(
+                Vec3::NEG_Z,
+                stats.tip_offset(animation.direction, progress).normalize(),
+            )
+        };
+        transform.rotation = match stats.phase_at(animation.elapsed) {
+            Some((SwingPhase::Windup, t)) => idle.slerp(aim(0.0), t),
+            Some((SwingPhase::Release, t)) => aim(t),
+            Some((SwingPhase::Recovery, t)) => aim(1.0).slerp(idle, t),
+            None => idle,
+        };
+    }
+}
+
+/// Draws the selected ranged weapon while the draw key is held and looses it on release. The
+/// number keys select the bow, crossbow or throwing axe.
+pub fn send_ranged(
+    mut client: NetworkClient,
+    keys: Res<Input<KeyCode>>,
+    countdown: Option<Res<RespawnCountdown>>,
+    players: Query<(), With<LogicalPlayer>>,
+    mut selected: Local<RangedWeaponKind>,
+) {
+    for (key, kind) in [
+        (KeyCode::Key1, RangedWeaponKind::Bow),
+        (KeyCode::Key2, RangedWeaponKind::Crossbow),
+        (KeyCode::Key3, RangedWeaponKind::ThrowingAxe),
+    ] {
+        if keys.just_pressed(key) {
+            *selected = kind;
+        }
+    }
+    if countdown.is_some() || players.is_empty() {
+        return;
+    }
+
+    if keys.just_pressed(KeyCode::E) {
+        client.send(&ClientMessage::Draw { weapon: *selected });
+    } else if keys.just_released(KeyCode::E) {
+        client.send(&ClientMessage::Loose);
+    }
+}
+
+/// How long projectiles stay stuck in the map.
+const STUCK_SECONDS: f32 = 30.0;
+
+/// Spawns projectiles launched by the server and settles them where they hit.
+pub fn handle_projectile_messages(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut messages: EventReader<FromServer<ServerMessage>>,
+    projectiles: Query<(Entity, &ProjectileId, &Transform)>,
+) {
+    // Projectiles spawned by earlier messages this frame, which queries don't see yet.
+    let mut spawned = Vec::new();
+
+    for FromServer { message } in messages.read() {
+        match message {
+            &ServerMessage::ProjectileSpawned {
+                id,
+                kind,
+                position,
+                velocity,
+            } => {
+                let info = ProjectileInfo {
+                    id,
+                    kind,
+                    position,
+                    velocity,
+                };
+                spawned.push(spawn_projectile(
+                    &mut commands,
+                    &mut meshes,
+                    &mut materials,
+                    &info,
+                ));
+            }
+            // Projectiles launched before this client joined.
+            ServerMessage::WorldState {
+                projectiles: infos, ..
+            } => {
+                for info in infos {
+                    if spawned.iter().any(|(id, _, _)| *id == info.id)
+                        || projectiles.iter().any(|(_, id, _)| *id == info.id)
+                    {
+                        continue;
+                    }
+                    spawned.push(spawn_projectile(
+                        &mut commands,
+                        &mut meshes,
+                        &mut materials,
+                        info,
+                    ));
+                }
+            }
+            &ServerMessage::ProjectileImpact {
+                id,
+                position,
+                stuck,
+            } => {
+                let found = spawned
+                    .iter()
+                    .find(|(spawned, _, _)| *spawned == id)
+                    .map(|(_, entity, rotation)| (*entity, *rotation))
+                    .or_else(|| {
+                        projectiles
+                            .iter()
+                            .find(|(_, projectile, _)| **projectile == id)
+                            .map(|(entity, _, transform)| (entity, transform.rotation))
+                    });
+                let Some((entity, rotation)) = found else {
+                    continue;
+                };
+
+                if stuck {
+                    commands
+                        .entity(entity)
+                        .remove::<ProjectileFlight>()
+                        .insert(StuckProjectile(Timer::from_seconds(
+                            STUCK_SECONDS,
+                            TimerMode::Once,
+                        )))
+                        .insert(Transform::from_translation(position).with_rotation(rotation));
+                } else {
+                    commands.entity(entity).despawn_recursive();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Spawns a flying projectile, returning its id, entity and rotation.
+fn spawn_projectile(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    info: &ProjectileInfo,
+) -> (ProjectileId, Entity, Quat) {
+    let size = match info.kind {
+        RangedWeaponKind::Bow => Vec3::new(0.02, 0.02, 0.8),
+        RangedWeaponKind::Crossbow => Vec3::new(0.02, 0.02, 0.4),
+        RangedWeaponKind::ThrowingAxe => Vec3::new(0.05, 0.25, 0.35),
+    };
+    let transform = Transform::from_translation(info.position).looking_to(info.velocity, Vec3::Y);
+    let entity = commands
+        .spawn((
+            PbrBundle {
+                mesh: meshes.add(shape::Box::new(size.x, size.y, size.z).into()),
+                material: materials.add(Color::rgb(0.45, 0.3, 0.15).into()),
+                transform,
+                ..default()
+            },
+            info.id,
+            ProjectileFlight {
+                kind: info.kind,
+                flight: Flight {
+                    position: info.position,
+                    velocity: info.velocity,
+                },
+                previous: info.position,
+            },
+        ))
+        .id();
+    (info.id, entity, transform.rotation)
+}
+
+/// Flies projectiles with the server's ballistics, one step per fixed tick like the server.
+pub fn simulate_projectiles(
+    time: Res<Time>,
+    settings: Res<MapSettings>,
+    mut flying: Query<&mut ProjectileFlight>,
+) {
+    let gravity = settings.gravity.unwrap_or(GRAVITY);
+
+    for mut projectile in flying.iter_mut() {
+        let stats = projectile.kind.stats();
+        projectile.previous = projectile.flight.position;
+        projectile
+            .flight
+            .step(&stats, gravity, time.delta_seconds());
+    }
+}
+
+/// Places flying projectiles between their last two fixed steps and removes stuck ones once they
+/// expire.
+pub fn render_projectiles(
+    mut commands: Commands,
+    time: Res<Time>,
+    fixed_time: Res<Time<Fixed>>,
+    mut flying: Query<(&ProjectileFlight, &mut Transform)>,
+    mut stuck: Query<(Entity, &mut StuckProjectile)>,
+) {
+    let alpha = fixed_time.overstep_percentage();
+
+    for (projectile, mut transform) in flying.iter_mut() {
+        let position = projectile.previous.lerp(projectile.flight.position, alpha);
+        *transform =
+            Transform::from_translation(position).looking_to(projectile.flight.velocity, Vec3::Y);
+    }
+
+    for (entity, mut stuck) in stuck.iter_mut() {
+        if stuck.0.tick(time.delta()).finished() {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Weapon held across the side of the body the guard covers.
+fn guard_rotation(direction: GuardDirection) -> Quat {
+    let tip = match direction {
+        GuardDirection::Up => Vec3::new(1.0, 0.5, -0.4),
+        GuardDirection::Left => Vec3::new(-0.6, 1.0, -0.3),
+        GuardDirection::Right => Vec3::new(0.4, 1.0, -0.3),
+        GuardDirection::Stab => Vec3::new(-0.5, 0.2, -1.0),
+    };
+    Quat::from_rotation_arc(Vec3::NEG_Z, tip.normalize())
+}
+
+/// Weapon held up and forward, between swings.
+fn rest_rotation() -> Quat {
+    Quat::from_rotation_arc(Vec3::NEG_Z, Vec3::new(0.0, 1.0, -0.5).normalize())
+}
+
+fn spawn_remote_player(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    info: PlayerInfo,
+) -> Entity {
+    let visibility = if info.dead {
+        Visibility::Hidden
+    } else {
+        Visibility::Inherited
+    };
+
+    commands
+        .spawn((
+            SpatialBundle {
+                visibility,
+                ..SpatialBundle::from_transform(Transform::from_translation(info.position))
+            },
+            RemotePlayer,
+            info.entity,
+            Name::new(info.name),
+            SnapshotBuffer::default(),
+        ))
+        .with_children(|parent| {
+            parent.spawn(PbrBundle {
+                mesh: meshes.add(shape::Capsule::default().into()),
+                material: materials.add(Color::rgb(0.8, 0.2, 0.2).into()),
+                transform: Transform::from_translation(Vec3::Y),
+                ..default()
+            });
+        })
+        .id()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_snapshots(
+    time: Res<Time>,
+    mut client: NetworkClient,
+    mut snapshots: EventReader<FromServer<Snapshot>>,
+    mut received: ResMut<ReceivedSnapshots>,
+    local_player: Option<Res<LocalPlayer>>,
+    entity_map: Res<NetworkEntityMap>,
+    mut buffers: Query<&mut SnapshotBuffer>,
+    mut local_player_states: EventWriter<LocalPlayerState>,
+) {
+    for FromServer {
+        message:
+            Snapshot {
+                tick,
+                baseline,
+                last_input,
+                players,
+                removed,
+            },
+    } in snapshots.read()
+    {
+        let (tick, last_input) = (*tick, *last_input);
+
+        if received.latest_tick().is_some_and(|latest| tick <= latest) {
+            continue;
+        }
+        let baseline = match *baseline {
+            Some(baseline) => match received.get(baseline) {
+                Some(baseline) => Some(baseline),
+                None => continue,
+            },
+            None => None,
+        };
+        let snapshot = WorldSnapshot::apply_delta(baseline, players, removed);
+
+        for (network_entity, player) in snapshot.players.iter() {
+            if let Some(mut buffer) = entity_map
+                .local(*network_entity)
+                .and_then(|entity| buffers.get_mut(entity).ok())
+            {
+                buffer.push(time.elapsed_seconds_f64(), player.into());
+            }
+        }
+
+        if let (Some(local_player), Some(sequence)) = (local_player.as_ref(), last_input) {
+            if let Some(player) = snapshot.players.get(&local_player.entity) {
+                let player = PlayerSnapshot::from(player);
+                local_player_states.send(LocalPlayerState {
+                    sequence,
+                    state: ControllerState {
+                        translation: player.position,
+                        vertical_velocity: player.velocity.y,
+                        grounded: player.grounded,
+                    },
+                });
+            }
+        }
+
+        received.push(tick, snapshot);
+
+        client.send(&SnapshotAck { tick });
+    }
+}
+
+pub fn interpolate_remote_players(
+    time: Res<Time>,
+    config: Res<ClientConfig>,
+    mut query: Query<(&mut Transform, &mut SnapshotBuffer), With<RemotePlayer>>,
+) {
+    let render_time = time.elapsed_seconds_f64() - config.interpolation_delay as f64;
+
+    for (mut transform, mut buffer) in query.iter_mut() {
+        buffer.discard_before(render_time);
+
+        if let Some(snapshot) = buffer.sample(render_time, config.max_extrapolation) {
+            transform.translation = snapshot.position;
+            transform.rotation = Quat::from_rotation_y(snapshot.yaw);
+        }
+    }
+}
+
+/// Corrections smaller than this are absorbed silently.
+const PREDICTION_TOLERANCE: f32 = 0.01;
+/// Corrections larger than this teleport the camera instead of being smoothed.
+const PREDICTION_MAX_SMOOTHING: f32 = 2.0;
+/// Rate at which the visual prediction error decays, per second.
+const PREDICTION_SMOOTHING_RATE: f32 = 15.0;
+
+pub fn reconcile_local_player(
+    mut states: EventReader<LocalPlayerState>,
+    mut history: ResMut<PredictionHistory>,
+    mut context: ResMut<RapierContext>,
+    mut players: Query<
+        (
+            Entity,
+            &mut Transform,
+            &mut Velocity,
+            &KinematicCharacterController,
+            &Collider,
+            &ControllerSettings,
+        ),
+        With<LogicalPlayer>,
+    >,
+    mut renders: Query<(&RenderPlayer, &mut PredictionError)>,
+) {
+    let Some(LocalPlayerState { sequence, state }) = states.read().last() else {
+        return;
+    };
+    let Ok((entity, mut transform, mut velocity, controller, collider, settings)) =
+        players.get_single_mut()
+    else {
+        return;
+    };
+
+    history.acknowledge(*sequence);
+
+    let mut predicted = *state;
+    for input in history.inputs.iter() {
+        predicted = simulate_step(
+            &mut context,
+            entity,
+            controller,
+            collider,
+            settings,
+            &input.input,
+            predicted,
+            input.dt,
+        );
+    }
+
+    let error = transform.translation - predicted.translation;
+    if error.length() < PREDICTION_TOLERANCE {
+        return;
+    }
+
+    transform.translation = predicted.translation;
+    velocity.linvel.y = predicted.vertical_velocity;
+
+    if error.length() < PREDICTION_MAX_SMOOTHING {
+        for (render_player, mut prediction_error) in renders.iter_mut() {
+            if render_player.logical_entity == entity {
+                prediction_error.0 += error;
+            }
+        }
+    }
+}
+
+pub fn smooth_prediction_error(
+    time: Res<Time>,
+    mut query: Query<(&mut Transform, &mut PredictionError), With<RenderPlayer>>,
+) {
+    let decay = (-PREDICTION_SMOOTHING_RATE * time.delta_seconds()).exp();
+
+    for (mut transform, mut prediction_error) in query.iter_mut() {
+        prediction_error.0 *= decay;
+        transform.translation += prediction_error.0;
+    }
+}
@@ -0,0 +1,95 @@
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use std::fmt;
+
+/// Largest message either side accepts. Bounds the allocations a peer can trigger.
+pub const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum DecodeError {
+    TooLarge(usize),
+    Malformed(bincode::Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooLarge(len) => write!(
+                f,
+                "message of {} bytes exceeds the limit of {} bytes",
+                len, MAX_MESSAGE_BYTES
+            ),
+            DecodeError::Malformed(err) => write!(f, "malformed message: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<bincode::Error> for DecodeError {
+    fn from(err: bincode::Error) -> Self {
+        DecodeError::Malformed(err)
+    }
+}
+
+/// Decodes a message written with `bincode::serialize`, rejecting oversized or trailing input
+/// instead of panicking.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, DecodeError> {
+    if bytes.len() > MAX_MESSAGE_BYTES {
+        return Err(DecodeError::TooLarge(bytes.len()));
+    }
+
+    Ok(bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .with_limit(MAX_MESSAGE_BYTES as u64)
+        .deserialize(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_serialized_message() {
+        let bytes = bincode::serialize(&(7u32, String::from("knight"))).unwrap();
+        let decoded: (u32, String) = decode(&bytes).unwrap();
+        assert_eq!(decoded, (7, String::from("knight")));
+    }
+
+    #[test]
+    fn rejects_oversized_payload() {
+        let bytes = vec![0; MAX_MESSAGE_BYTES + 1];
+        assert!(matches!(
+            decode::<u32>(&bytes),
+            Err(DecodeError::TooLarge(len)) if len == MAX_MESSAGE_BYTES + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut bytes = bincode::serialize(&7u32).unwrap();
+        bytes.push(0);
+        assert!(matches!(
+            decode::<u32>(&bytes),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_length_prefix_beyond_limit() {
+        let bytes = bincode::serialize(&u64::MAX).unwrap();
+        assert!(matches!(
+            decode::<Vec<u8>>(&bytes),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_payload() {
+        assert!(matches!(
+            decode::<u64>(&[1, 2, 3]),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,335 @@
+use crate::is_newer_sequence;
+use bevy::prelude::*;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{f32::consts::PI, fmt};
+
+/// Height above a player's feet that swings pivot around, about the shoulders.
+pub const SWING_ORIGIN_HEIGHT: f32 = 1.5;
+/// Seconds after raising a guard during which a block becomes a parry.
+pub const PARRY_WINDOW: f32 = 0.2;
+/// Seconds a parried attacker cannot attack or block.
+pub const STAGGER_SECONDS: f32 = 0.6;
+/// Mouse movement below this length picks no direction.
+const MIN_SWIPE: f32 = 4.0;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum WeaponKind {
+    #[default]
+    Sword,
+    Axe,
+    Mace,
+}
+
+/// Timings and reach of a weapon, the same on the client and the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WeaponStats {
+    /// Seconds spent raising the weapon before it can hit.
+    pub windup: f32,
+    /// Seconds the weapon travels along its arc and deals damage.
+    pub release: f32,
+    /// Seconds before the next attack.
+    pub recovery: f32,
+    /// Distance from the shoulder to the tip.
+    pub reach: f32,
+    /// Damage of a hit in the middle of the swing.
+    pub damage: f32,
+    /// Angle in radians covered by slashes and overheads.
+    pub arc: f32,
+}
+
+impl WeaponKind {
+    pub fn stats(self) -> WeaponStats {
+        match self {
+            WeaponKind::Sword => WeaponStats {
+                windup: 0.35,
+                release: 0.25,
+                recovery: 0.4,
+                reach: 1.4,
+                damage: 45.0,
+                arc: 110f32.to_radians(),
+            },
+            WeaponKind::Axe => WeaponStats {
+                windup: 0.45,
+                release: 0.25,
+                recovery: 0.55,
+                reach: 1.2,
+                damage: 60.0,
+                arc: 100f32.to_radians(),
+            },
+            WeaponKind::Mace => WeaponStats {
+                windup: 0.5,
+                release: 0.3,
+                recovery: 0.6,
+                reach: 1.1,
+                damage: 55.0,
+                arc: 90f32.to_radians(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SwingDirection {
+    /// Slash from the attacker's left to their right.
+    Left,
+    /// Slash from the attacker's right to their left.
+    Right,
+    Overhead,
+    Thrust,
+}
+
+impl SwingDirection {
+    /// Direction of an attack following the mouse: a slash towards the side the mouse moves to,
+    /// an overhead when it moves down and a thrust when it moves up.
+    pub fn from_swipe(swipe: Vec2) -> Option<Self> {
+        if swipe.length() < MIN_SWIPE {
+            None
+        } else if swipe.x.abs() > swipe.y.abs() {
+            Some(if swipe.x < 0.0 {
+                SwingDirection::Right
+            } else {
+                SwingDirection::Left
+            })
+        } else if swipe.y > 0.0 {
+            Some(SwingDirection::Overhead)
+        } else {
+            Some(SwingDirection::Thrust)
+        }
+    }
+
+    /// Guard that blocks this attack. Slashes from the attacker's left arrive on the defender's
+    /// right.
+    pub fn threatens(self) -> GuardDirection {
+        match self {
+            SwingDirection::Left => GuardDirection::Right,
+            SwingDirection::Right => GuardDirection::Left,
+            SwingDirection::Overhead => GuardDirection::Up,
+            SwingDirection::Thrust => GuardDirection::Stab,
+        }
+    }
+
+    /// Side of the attacker's own body this swing starts from, which it guards while winding up.
+    pub fn covers(self) -> GuardDirection {
+        match self {
+            SwingDirection::Left => GuardDirection::Left,
+            SwingDirection::Right => GuardDirection::Right,
+            SwingDirection::Overhead => GuardDirection::Up,
+            SwingDirection::Thrust => GuardDirection::Stab,
+        }
+    }
+}
+
+/// Side of the body a guard covers, named from the defender's point of view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GuardDirection {
+    Up,
+    Left,
+    Right,
+    Stab,
+}
+
+impl GuardDirection {
+    /// Guard following the mouse: towards the side it moves to, up when it moves up and against
+    /// stabs when it moves down.
+    pub fn from_swipe(swipe: Vec2) -> Option<Self> {
+        if swipe.length() < MIN_SWIPE {
+            None
+        } else if swipe.x.abs() > swipe.y.abs() {
+            Some(if swipe.x < 0.0 {
+                GuardDirection::Left
+            } else {
+                GuardDirection::Right
+            })
+        } else if swipe.y < 0.0 {
+            Some(GuardDirection::Up)
+        } else {
+            Some(GuardDirection::Stab)
+        }
+    }
+}
+
+/// How a defender stopped an attack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Defense {
+    /// Guard raised in the right direction. The attack goes into recovery.
+    Blocked,
+    /// Guard raised in the right direction just before the hit. The attacker is staggered.
+    Parried,
+    /// Counter-attack wound up from the threatened side. The attack goes into recovery and the
+    /// counter continues.
+    Chambered,
+}
+
+impl fmt::Display for Defense {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Defense::Blocked => write!(f, "blocked"),
+            Defense::Parried => write!(f, "parried"),
+            Defense::Chambered => write!(f, "chambered"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwingPhase {
+    Windup,
+    Release,
+    Recovery,
+}
+
+impl WeaponStats {
+    pub fn duration(&self) -> f32 {
+        self.windup + self.release + self.recovery
+    }
+
+    /// Phase `elapsed` seconds into a swing with the progress through it from 0 to 1, or `None`
+    /// once the swing is over.
+    pub fn phase_at(&self, elapsed: f32) -> Option<(SwingPhase, f32)> {
+        if elapsed < self.windup {
+            Some((SwingPhase::Windup, elapsed / self.windup))
+        } else if elapsed < self.windup + self.release {
+            Some((SwingPhase::Release, (elapsed - self.windup) / self.release))
+        } else if elapsed < self.duration() {
+            let recovered = elapsed - self.windup - self.release;
+            Some((SwingPhase::Recovery, recovered / self.recovery))
+        } else {
+            None
+        }
+    }
+
+    /// Progress through the release `elapsed` seconds into a swing, clamped to 0 to 1.
+    pub fn release_progress(&self, elapsed: f32) -> f32 {
+        ((elapsed - self.windup) / self.release).clamp(0.0, 1.0)
+    }
+
+    /// Damage of a hit landing at `progress` through the release. Hits in the middle of the arc
+    /// deal full damage, glancing hits at its ends less than half.
+    pub fn damage_at(&self, progress: f32) -> f32 {
+        self.damage * (0.4 + 0.6 * (PI * progress).sin())
+    }
+
+    /// Position of the tip at `progress` through the release, relative to the shoulder in view
+    /// space where -Z is forward.
+    pub fn tip_offset(&self, direction: SwingDirection, progress: f32) -> Vec3 {
+        let half_arc = self.arc / 2.0;
+        let angle = -half_arc + self.arc * progress;
+        let offset = match direction {
+            SwingDirection::Left => Vec3::new(angle.sin(), 0.0, -angle.cos()),
+            SwingDirection::Right => Vec3::new(-angle.sin(), 0.0, -angle.cos()),
+            SwingDirection::Overhead => Vec3::new(0.0, -angle.sin(), -angle.cos()),
+            SwingDirection::Thrust => Vec3::new(0.0, 0.0, -(0.4 + 0.6 * progress)),
+        };
+        offset * self.reach
+    }
+}
+
+/// Melee weapon of a player and the swing in progress.
+#[derive(Debug, Default, Component)]
+pub struct MeleeWeapon {
+    pub kind: WeaponKind,
+    pub swing: Option<Swing>,
+    /// Input sequence of the last attack started, older attacks are dropped.
+    pub last_attack: Option<u32>,
+    pub guard: Option<Guard>,
+    /// Seconds left before a parried player can attack or block again.
+    pub stagger: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Guard {
+    pub direction: GuardDirection,
+    /// Seconds since the guard was raised or changed direction.
+    pub held: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Swing {
+    pub direction: SwingDirection,
+    /// Seconds since the swing started.
+    pub elapsed: f32,
+    /// Players already hit, who are not hit again by the same swing.
+    pub hit: Vec<Entity>,
+}
+
+impl MeleeWeapon {
+    /// Starts a swing unless one is in progress, the player is staggered or the attack is older
+    /// than the last one. Attacking lowers the guard.
+    pub fn attack(&mut self, direction: SwingDirection, sequence: u32) -> bool {
+        let stale = self
+            .last_attack
+            .is_some_and(|last| !is_newer_sequence(sequence, last));
+        if self.swing.is_some() || self.stagger > 0.0 || stale {
+            return false;
+        }
+
+        self.last_attack = Some(sequence);
+        self.guard = None;
+        self.swing = Some(Swing {
+            direction,
+            elapsed: 0.0,
+            hit: Vec::new(),
+        });
+        true
+    }
+
+    /// Raises, redirects or with `None` lowers the guard. Guards cannot be raised mid-swing or
+    /// while staggered, except to feint during the windup.
+    pub fn set_guard(&mut self, direction: Option<GuardDirection>) -> bool {
+        let Some(direction) = direction else {
+            return self.guard.take().is_some();
+        };
+        if self.stagger > 0.0 || self.guard.is_some_and(|guard| guard.direction == direction) {
+            return false;
+        }
+        if self.swing.is_some() && !self.feint() {
+            return false;
+        }
+
+        self.guard = Some(Guard {
+            direction,
+            held: 0.0,
+        });
+        true
+    }
+
+    /// Cancels a swing that is still winding up.
+    pub fn feint(&mut self) -> bool {
+        let winding_up = self.swing.as_ref().is_some_and(|swing| {
+            matches!(
+                self.kind.stats().phase_at(swing.elapsed),
+                Some((SwingPhase::Windup, _))
+            )
+        });
+        if winding_up {
+            self.swing = None;
+        }
+        winding_up
+    }
+
+    /// How this player, facing the attacker, stops an attack in `direction`, if at all.
+    pub fn defend(&self, direction: SwingDirection) -> Option<Defense> {
+        if let Some(guard) = self.guard {
+            if guard.direction == direction.threatens() {
+                return Some(if guard.held <= PARRY_WINDOW {
+                    Defense::Parried
+                } else {
+                    Defense::Blocked
+                });
+            }
+        }
+
+        let swing = self.swing.as_ref()?;
+        let (phase, _) = self.kind.stats().phase_at(swing.elapsed)?;
+        (phase == SwingPhase::Windup && swing.direction.covers() == direction.threatens())
+            .then_some(Defense::Chambered)
+    }
+
+    /// Advances guard and stagger timers by `dt` seconds.
+    pub fn tick(&mut self, dt: f32) {
+        self.stagger = (self.stagger - dt).max(0.0);
+        if let Some(guard) = self.guard.as_mut() {
+            guard.held += dt;
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use serde::de::DeserializeOwned;
+use std::{fmt, fs, io, path::Path};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Ron(ron::error::SpannedError),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "could not read config file: {}", err),
+            ConfigError::Ron(err) => write!(f, "could not parse config file: {}", err),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> Self {
+        ConfigError::Io(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for ConfigError {
+    fn from(err: ron::error::SpannedError) -> Self {
+        ConfigError::Ron(err)
+    }
+}
+
+/// Reads a RON config file. Missing fields fall back to the config's defaults.
+pub fn read_config_file<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<T, ConfigError> {
+    let contents = fs::read_to_string(path)?;
+    Ok(ron::from_str(&contents)?)
+}
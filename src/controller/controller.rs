@@ -1,8 +1,10 @@
-use bevy::{input::mouse::MouseMotion, prelude::*};
+use crate::simulation::SimulationSet;
+use bevy::{input::mouse::MouseMotion, prelude::*, transform::TransformSystem};
 use bevy_rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::f32::consts::*;
 
-#[derive(Component, Default, Debug)]
+#[derive(Component, Default, Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct FpsControllerInput {
     pub jump: bool,
     pub pitch: f32,
@@ -10,6 +12,16 @@ pub struct FpsControllerInput {
     pub movement: Vec2,
 }
 
+/// Recent mouse movement in pixels, fading out over a fraction of a second, that picks the
+/// direction of attacks and guards.
+#[derive(Component, Default, Debug, Clone, Copy)]
+pub struct MouseSwipe(pub Vec2);
+
+impl MouseSwipe {
+    /// Seconds for past movement to fade to half.
+    const HALF_LIFE: f32 = 0.1;
+}
+
 #[derive(Component)]
 pub struct ControllerSettings {
     pub enable_input: bool,
@@ -59,6 +71,7 @@ pub struct FpsCharacterController {
     transform: TransformBundle,
     collider: Collider,
     input: FpsControllerInput,
+    swipe: MouseSwipe,
     logical_player: LogicalPlayer,
 }
 
@@ -80,17 +93,36 @@ impl Default for FpsCharacterController {
             controller_settings: ControllerSettings::default(),
             velocity: Velocity::default(),
             transform: TransformBundle::default(),
-            collider: Collider::capsule(Vec3::Y * 0.5, Vec3::Y * 1.5, 0.5),
+            collider: Self::collider(),
             input: FpsControllerInput {
                 pitch: -TAU / 12.0,
                 yaw: TAU * 5.0 / 8.0,
                 ..default()
             },
+            swipe: MouseSwipe::default(),
             logical_player: LogicalPlayer,
         }
     }
 }
 
+impl FpsCharacterController {
+    /// Shape of every player, standing on the origin of its transform.
+    pub fn collider() -> Collider {
+        Collider::capsule(Vec3::Y * 0.5, Vec3::Y * 1.5, 0.5)
+    }
+
+    pub fn with_translation(mut self, translation: Vec3) -> Self {
+        self.transform = TransformBundle::from_transform(Transform::from_translation(translation));
+        self
+    }
+
+    /// Disables local keyboard and mouse input, for controllers driven by network input.
+    pub fn without_input(mut self) -> Self {
+        self.controller_settings.enable_input = false;
+        self
+    }
+}
+
 pub struct FpsControllerPlugin;
 
 impl Plugin for FpsControllerPlugin {
@@ -99,13 +131,7 @@ impl Plugin for FpsControllerPlugin {
 
         app.add_systems(
             PreUpdate,
-            (
-                fps_controller_input,
-                fps_controller_move,
-                fps_controller_update,
-                fps_controller_render,
-            )
-                .chain()
+            fps_controller_input
                 .after(mouse::mouse_button_input_system)
                 .after(keyboard::keyboard_input_system)
                 .after(gamepad::gamepad_axis_event_system)
@@ -113,6 +139,16 @@ impl Plugin for FpsControllerPlugin {
                 .after(gamepad::gamepad_connection_system)
                 .after(gamepad::gamepad_event_system)
                 .after(touch::touch_screen_input_system),
+        )
+        .add_systems(
+            FixedUpdate,
+            (fps_controller_move, fps_controller_update)
+                .chain()
+                .in_set(SimulationSet::Movement),
+        )
+        .add_systems(
+            PostUpdate,
+            fps_controller_render.before(TransformSystem::TransformPropagate),
         );
     }
 }
@@ -120,11 +156,16 @@ impl Plugin for FpsControllerPlugin {
 const ANGLE_EPSILON: f32 = 0.001953125;
 
 pub fn fps_controller_input(
+    time: Res<Time>,
     key_input: Res<Input<KeyCode>>,
     mut mouse_events: EventReader<MouseMotion>,
-    mut query: Query<(&ControllerSettings, &mut FpsControllerInput)>,
+    mut query: Query<(
+        &ControllerSettings,
+        &mut FpsControllerInput,
+        &mut MouseSwipe,
+    )>,
 ) {
-    for (settings, mut input) in query.iter_mut() {
+    for (settings, mut input, mut swipe) in query.iter_mut() {
         if !settings.enable_input {
             continue;
         }
@@ -133,6 +174,8 @@ pub fn fps_controller_input(
         for mouse_event in mouse_events.read() {
             mouse_delta += mouse_event.delta;
         }
+        let fade = 0.5_f32.powf(time.delta_seconds() / MouseSwipe::HALF_LIFE);
+        swipe.0 = swipe.0 * fade + mouse_delta;
         mouse_delta *= settings.sensitivity;
 
         input.pitch = (input.pitch - mouse_delta.y)
@@ -162,15 +205,8 @@ pub fn fps_controller_update(
     let dt = time.delta_seconds();
 
     for (input, settings, mut velocity, output) in query.iter_mut() {
-        if output.grounded {
-            if input.jump {
-                velocity.linvel.y = settings.jump_force;
-            } else {
-                velocity.linvel.y = -0.5;
-            }
-        } else {
-            velocity.linvel.y -= settings.gravity * dt;
-        }
+        velocity.linvel.y =
+            vertical_velocity(input, settings, velocity.linvel.y, output.grounded, dt);
     }
 }
 
@@ -186,17 +222,103 @@ pub fn fps_controller_move(
     let dt = time.delta_seconds();
 
     for (input, settings, velocity, mut controller) in query.iter_mut() {
-        let yaw = Quat::from_rotation_y(input.yaw);
-        let direction = yaw * Vec3::X * input.movement.y - yaw * Vec3::Z * input.movement.x;
-        let direction = if direction.length_squared() > 0.0 {
-            direction.normalize()
+        controller.translation = Some(desired_translation(input, settings, velocity.linvel.y, dt));
+    }
+}
+
+/// Vertical velocity after one step, as applied by [`fps_controller_update`].
+pub fn vertical_velocity(
+    input: &FpsControllerInput,
+    settings: &ControllerSettings,
+    vertical_velocity: f32,
+    grounded: bool,
+    dt: f32,
+) -> f32 {
+    if grounded {
+        if input.jump {
+            settings.jump_force
         } else {
-            direction
-        };
-        let velocity = direction * settings.walk_speed + Vec3::Y * velocity.linvel.y;
-        let translation = velocity * dt;
+            -0.5
+        }
+    } else {
+        vertical_velocity - settings.gravity * dt
+    }
+}
+
+/// Translation the character controller is asked to perform in one step, as applied by
+/// [`fps_controller_move`].
+pub fn desired_translation(
+    input: &FpsControllerInput,
+    settings: &ControllerSettings,
+    vertical_velocity: f32,
+    dt: f32,
+) -> Vec3 {
+    let yaw = Quat::from_rotation_y(input.yaw);
+    let direction = yaw * Vec3::X * input.movement.y - yaw * Vec3::Z * input.movement.x;
+    let direction = if direction.length_squared() > 0.0 {
+        direction.normalize()
+    } else {
+        direction
+    };
+    let velocity = direction * settings.walk_speed + Vec3::Y * vertical_velocity;
+
+    velocity * dt
+}
+
+/// Simulation state of a logical player, enough to replay inputs from it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ControllerState {
+    pub translation: Vec3,
+    pub vertical_velocity: f32,
+    pub grounded: bool,
+}
+
+/// Runs one controller step outside of the schedule, moving the collider through the physics
+/// world the same way the [`KinematicCharacterController`] would.
+#[allow(clippy::too_many_arguments)]
+pub fn simulate_step(
+    context: &mut RapierContext,
+    entity: Entity,
+    controller: &KinematicCharacterController,
+    collider: &Collider,
+    settings: &ControllerSettings,
+    input: &FpsControllerInput,
+    state: ControllerState,
+    dt: f32,
+) -> ControllerState {
+    let translation = desired_translation(input, settings, state.vertical_velocity, dt);
+    let vertical_velocity =
+        vertical_velocity(input, settings, state.vertical_velocity, state.grounded, dt);
+    let options = MoveShapeOptions {
+        up: controller.up,
+        offset: controller.offset,
+        slide: controller.slide,
+        autostep: controller.autostep,
+        max_slope_climb_angle: controller.max_slope_climb_angle,
+        min_slope_slide_angle: controller.min_slope_slide_angle,
+        apply_impulse_to_dynamic_bodies: false,
+        snap_to_ground: controller.snap_to_ground,
+    };
+    let filter = QueryFilter::new()
+        .exclude_sensors()
+        .exclude_collider(entity)
+        .exclude_rigid_body(entity);
+
+    let output = context.move_shape(
+        translation,
+        collider,
+        state.translation,
+        Quat::IDENTITY,
+        0.0,
+        &options,
+        filter,
+        |_| {},
+    );
 
-        controller.translation = Some(translation);
+    ControllerState {
+        translation: state.translation + output.effective_translation,
+        vertical_velocity,
+        grounded: output.grounded,
     }
 }
 
@@ -1,3 +1,8 @@
+#[allow(clippy::module_inception)]
 pub mod controller;
 
-pub use controller::{FpsCharacterController, FpsControllerPlugin, RenderPlayer};
+pub use controller::{
+    fps_controller_move, fps_controller_render, simulate_step, ControllerSettings, ControllerState,
+    FpsCharacterController, FpsControllerInput, FpsControllerPlugin, LogicalPlayer, MouseSwipe,
+    RenderPlayer,
+};
@@ -1,29 +1,98 @@
+#![allow(clippy::type_complexity)]
+
+pub mod auth;
+pub mod channel;
 pub mod client;
-pub mod server;
+pub mod codec;
+pub mod combat;
+pub mod config;
 pub mod controller;
+pub mod map;
+pub mod projectile;
+pub mod server;
+pub mod simulation;
+pub mod snapshot;
 
 use bevy::prelude::*;
-use bevy_renet::renet::{ChannelConfig, ClientId, ConnectionConfig, SendType};
+use bevy_renet::renet::{transport::NETCODE_USER_DATA_BYTES, ClientId, SendType};
+use channel::NetworkMessageAppExt;
+use combat::{Defense, GuardDirection, SwingDirection, WeaponKind};
+use controller::FpsControllerInput;
+use projectile::{ProjectileId, RangedWeaponKind};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use snapshot::PlayerDelta;
+use std::{collections::HashMap, fmt, time::Duration};
 
+/// Netcode protocol id. Clients with a different id are dropped by netcode without a reply, so it
+/// stays fixed and message layout changes bump [`PROTOCOL_VERSION`] instead.
 pub const PROTOCOL_ID: u64 = 0;
 
-pub enum ClientChannel {
-    ClientMessage,
+/// Version of the `ClientMessage`/`ServerMessage` layouts, checked during the handshake.
+pub const PROTOCOL_VERSION: u32 = 9;
+
+/// Registers every network message on its own channel. The client and the server both add it,
+/// which keeps their channel ids in sync.
+pub struct ProtocolPlugin;
+
+impl Plugin for ProtocolPlugin {
+    fn build(&self, app: &mut App) {
+        let reliable = SendType::ReliableOrdered {
+            resend_time: Duration::from_millis(200),
+        };
+
+        app.add_client_message::<ClientMessage>(reliable.clone())
+            .add_client_message::<PlayerInput>(SendType::Unreliable)
+            .add_client_message::<SnapshotAck>(SendType::Unreliable)
+            .add_server_message::<ServerMessage>(reliable)
+            .add_server_message::<Snapshot>(SendType::Unreliable);
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Component)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientMessage {
-    SpawnMe,
+    /// First message of every connection. Nothing else is accepted until the server answers
+    /// with [`ServerMessage::Welcome`].
+    Hello { version: u32, name: String },
+    /// Spawns the sender's player once, with a melee weapon the server allows.
+    SpawnMe { weapon: WeaponKind },
+    /// Starts a melee swing. `sequence` is the last input sent before it, so the server can drop
+    /// attacks that arrive out of order.
+    Attack {
+        direction: SwingDirection,
+        sequence: u32,
+    },
+    /// Raises or redirects the guard, or lowers it with `None`. Raising it during a windup feints.
+    Guard { direction: Option<GuardDirection> },
+    /// Cancels the swing in progress if it is still winding up.
+    Feint,
+    /// Starts drawing a ranged weapon. The server times the draw.
+    Draw { weapon: RangedWeaponKind },
+    /// Looses the drawn ranged weapon where the player is looking.
+    Loose,
+}
+
+/// Input of the local player, sent every tick.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerInput {
+    pub sequence: u32,
+    pub input: FpsControllerInput,
 }
 
-pub enum ServerChannel {
-    ServerMessage,
+/// Newest snapshot decoded by the client. The server delta-encodes further snapshots against it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotAck {
+    pub tick: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Component)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerMessage {
+    Welcome {
+        info: ServerInfo,
+    },
+    /// The server disconnects the client shortly after sending this.
+    Rejected {
+        reason: RejectReason,
+    },
     PlayerConnected {
         id: ClientId,
     },
@@ -31,61 +100,241 @@ pub enum ServerMessage {
         id: ClientId,
     },
     SpawnPlayer {
-        server_entity: Entity,
+        entity: NetworkEntity,
+        name: String,
         position: Vec3,
     },
+    /// Every player and projectile already in the world, sent to a client right before its own
+    /// `SpawnHim`.
+    WorldState {
+        players: Vec<PlayerInfo>,
+        projectiles: Vec<ProjectileInfo>,
+    },
     SpawnHim {
-        server_entity: Entity,
+        entity: NetworkEntity,
+        position: Vec3,
+    },
+    Despawn {
+        entity: NetworkEntity,
+    },
+    PlayerDied {
+        victim: NetworkEntity,
+        /// `None` when nobody caused the death, e.g. a kill volume.
+        killer: Option<NetworkEntity>,
+        cause: DamageType,
+        /// Seconds until the victim respawns.
+        respawn_in: f32,
+    },
+    PlayerRespawned {
+        entity: NetworkEntity,
+        position: Vec3,
+    },
+    /// A player started a melee swing. Clients animate it from the weapon's timings.
+    Swing {
+        attacker: NetworkEntity,
+        weapon: WeaponKind,
+        direction: SwingDirection,
+    },
+    /// A player raised, redirected or with `None` lowered their guard.
+    Guard {
+        entity: NetworkEntity,
+        direction: Option<GuardDirection>,
+    },
+    /// A player cancelled their swing during the windup.
+    Feint {
+        attacker: NetworkEntity,
+    },
+    /// A swing was stopped by the defender instead of hitting them. The attacker's swing is over,
+    /// and after a parry they are staggered. Only sent to the two combatants.
+    Clash {
+        attacker: NetworkEntity,
+        defender: NetworkEntity,
+        defense: Defense,
+    },
+    /// A projectile was launched. Clients simulate its flight locally until its impact.
+    ProjectileSpawned {
+        id: ProjectileId,
+        kind: RangedWeaponKind,
+        position: Vec3,
+        velocity: Vec3,
+    },
+    /// A projectile hit something or expired at `position`, and either stuck there or is gone.
+    ProjectileImpact {
+        id: ProjectileId,
         position: Vec3,
+        stuck: bool,
     },
 }
 
-impl From<ClientChannel> for u8 {
-    fn from(channel_id: ClientChannel) -> Self {
-        match channel_id {
-            ClientChannel::ClientMessage => 0,
-        }
+/// Sent periodically, delta-encoded against the `baseline` tick acknowledged by the client, or
+/// complete when there is none.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub tick: u64,
+    pub baseline: Option<u64>,
+    /// Last input of the receiving client processed before the snapshot was taken.
+    pub last_input: Option<u32>,
+    pub players: Vec<PlayerDelta>,
+    pub removed: Vec<NetworkEntity>,
+}
+
+/// Stable id of a replicated entity, the same on the server and on every client.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, Component,
+)]
+pub struct NetworkEntity(pub u32);
+
+/// Two-way mapping between network ids and the local entities they are replicated to.
+#[derive(Debug, Default, Resource)]
+pub struct NetworkEntityMap {
+    to_local: HashMap<NetworkEntity, Entity>,
+    to_network: HashMap<Entity, NetworkEntity>,
+}
+
+impl NetworkEntityMap {
+    pub fn insert(&mut self, network_entity: NetworkEntity, entity: Entity) {
+        self.to_local.insert(network_entity, entity);
+        self.to_network.insert(entity, network_entity);
+    }
+
+    pub fn local(&self, network_entity: NetworkEntity) -> Option<Entity> {
+        self.to_local.get(&network_entity).copied()
+    }
+
+    pub fn network(&self, entity: Entity) -> Option<NetworkEntity> {
+        self.to_network.get(&entity).copied()
     }
+
+    pub fn remove_network(&mut self, network_entity: NetworkEntity) -> Option<Entity> {
+        let entity = self.to_local.remove(&network_entity)?;
+        self.to_network.remove(&entity);
+        Some(entity)
+    }
+
+    pub fn remove_local(&mut self, entity: Entity) -> Option<NetworkEntity> {
+        let network_entity = self.to_network.remove(&entity)?;
+        self.to_local.remove(&network_entity);
+        Some(network_entity)
+    }
+
+    pub fn clear(&mut self) {
+        self.to_local.clear();
+        self.to_network.clear();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Resource)]
+pub struct ServerInfo {
+    pub map: String,
+    pub tick_rate: f64,
+    pub snapshot_rate: f64,
+    pub max_players: usize,
 }
 
-impl ClientChannel {
-    pub fn channels_config() -> Vec<ChannelConfig> {
-        vec![ChannelConfig {
-            channel_id: Self::ClientMessage.into(),
-            max_memory_usage_bytes: 10 * 1024 * 1024,
-            send_type: SendType::ReliableOrdered {
-                resend_time: Duration::from_millis(200),
-            },
-        }]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectReason {
+    VersionMismatch {
+        server: u32,
+        client: u32,
+    },
+    ServerFull,
+    Banned,
+    /// Too many malformed or unexpected messages.
+    ProtocolViolation,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::VersionMismatch { server, client } => write!(
+                f,
+                "version mismatch, the server runs protocol {} and this client {}",
+                server, client
+            ),
+            RejectReason::ServerFull => write!(f, "the server is full"),
+            RejectReason::Banned => write!(f, "you are banned from this server"),
+            RejectReason::ProtocolViolation => {
+                write!(f, "too many invalid messages were sent to the server")
+            }
+        }
     }
 }
 
-impl From<ServerChannel> for u8 {
-    fn from(channel_id: ServerChannel) -> Self {
-        match channel_id {
-            ServerChannel::ServerMessage => 0,
+/// What dealt damage to a player.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DamageType {
+    Melee,
+    Projectile,
+    /// Hazards of the map such as kill volumes.
+    Environment,
+}
+
+impl fmt::Display for DamageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DamageType::Melee => write!(f, "melee"),
+            DamageType::Projectile => write!(f, "projectile"),
+            DamageType::Environment => write!(f, "environment"),
         }
     }
 }
 
-impl ServerChannel {
-    pub fn channels_config() -> Vec<ChannelConfig> {
-        vec![ChannelConfig {
-            channel_id: Self::ServerMessage.into(),
-            max_memory_usage_bytes: 10 * 1024 * 1024,
-            send_type: SendType::ReliableOrdered {
-                resend_time: Duration::from_millis(200),
-            },
-        }]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerInfo {
+    pub entity: NetworkEntity,
+    pub name: String,
+    pub position: Vec3,
+    /// Dead players stay hidden until they respawn.
+    pub dead: bool,
+}
+
+/// A projectile in flight, as `ServerMessage::ProjectileSpawned` would describe it now.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProjectileInfo {
+    pub id: ProjectileId,
+    pub kind: RangedWeaponKind,
+    pub position: Vec3,
+    pub velocity: Vec3,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PlayerSnapshot {
+    pub position: Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub velocity: Vec3,
+    pub grounded: bool,
+}
+
+/// Whether input sequence `sequence` was sent after `than`. Sequences wrap around, so anything
+/// up to half the range ahead counts as newer.
+pub fn is_newer_sequence(sequence: u32, than: u32) -> bool {
+    (sequence.wrapping_sub(than) as i32) > 0
+}
+
+/// Packs the player name into the netcode user data sent along with the connection request.
+/// Names that do not fit are truncated.
+pub fn player_name_to_user_data(name: &str) -> [u8; NETCODE_USER_DATA_BYTES] {
+    let mut user_data = [0u8; NETCODE_USER_DATA_BYTES];
+    let mut len = name.len().min(NETCODE_USER_DATA_BYTES - 8);
+    while !name.is_char_boundary(len) {
+        len -= 1;
     }
+
+    user_data[0..8].copy_from_slice(&(len as u64).to_le_bytes());
+    user_data[8..len + 8].copy_from_slice(&name.as_bytes()[..len]);
+    user_data
 }
 
-pub fn connection_config() -> ConnectionConfig {
-    ConnectionConfig {
-        available_bytes_per_tick: 1024 * 1024,
-        client_channels_config: ClientChannel::channels_config(),
-        server_channels_config: ServerChannel::channels_config(),
+pub fn player_name_from_user_data(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> Option<String> {
+    let mut len_bytes = [0u8; 8];
+    len_bytes.copy_from_slice(&user_data[0..8]);
+    let len = u64::from_le_bytes(len_bytes) as usize;
+    if len > NETCODE_USER_DATA_BYTES - 8 {
+        return None;
     }
+
+    String::from_utf8(user_data[8..len + 8].to_vec()).ok()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
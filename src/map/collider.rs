@@ -0,0 +1,335 @@
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::{Indices, VertexAttributeValues},
+        primitives::Aabb,
+    },
+};
+use bevy_rapier3d::{
+    parry::transformation::{convex_hull, vhacd::VHACD},
+    prelude::*,
+    rapier::prelude::{Point, Real, SharedShape, TriMeshFlags},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, fs, io, path::Path};
+
+/// Shape a map node's meshes collide with, set by the node's `collider` extra or name suffix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColliderShape {
+    /// Exact, but expensive and without an inside, so thin walls can be tunneled through.
+    Trimesh,
+    ConvexHull,
+    /// Convex parts approximating concave meshes, slow to compute.
+    ConvexDecomposition,
+    /// Shapes fitted to the mesh bounds.
+    Cuboid,
+    Ball,
+    Capsule,
+}
+
+impl ColliderShape {
+    const SUFFIXES: [(&'static str, ColliderShape); 6] = [
+        ("_trimesh", ColliderShape::Trimesh),
+        ("_convex_hull", ColliderShape::ConvexHull),
+        ("_convex_decomposition", ColliderShape::ConvexDecomposition),
+        ("_cuboid", ColliderShape::Cuboid),
+        ("_ball", ColliderShape::Ball),
+        ("_capsule", ColliderShape::Capsule),
+    ];
+
+    /// The shape named by the suffix of a node's name, e.g. `crate_cuboid`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::SUFFIXES
+            .into_iter()
+            .find(|(suffix, _)| name.ends_with(suffix))
+            .map(|(_, shape)| shape)
+    }
+}
+
+/// Vertices and triangles of a mesh primitive.
+pub struct MeshData {
+    pub vertices: Vec<Vec3>,
+    pub indices: Vec<[u32; 3]>,
+}
+
+impl MeshData {
+    pub fn from_mesh(mesh: &Mesh) -> Option<Self> {
+        let vertices = match mesh.attribute(Mesh::ATTRIBUTE_POSITION)? {
+            VertexAttributeValues::Float32x3(vertices) => {
+                vertices.iter().copied().map(Vec3::from_array).collect()
+            }
+            _ => return None,
+        };
+        let indices = match mesh.indices()? {
+            Indices::U16(indices) => indices
+                .chunks_exact(3)
+                .map(|i| [i[0] as u32, i[1] as u32, i[2] as u32])
+                .collect(),
+            Indices::U32(indices) => indices
+                .chunks_exact(3)
+                .map(|i| [i[0], i[1], i[2]])
+                .collect(),
+        };
+
+        Some(Self { vertices, indices })
+    }
+
+    /// FNV-1a hash of the geometry, telling whether a cached collider is stale.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hash = 0xcbf29ce484222325_u64;
+        let vertices = self
+            .vertices
+            .iter()
+            .flat_map(|v| v.to_array().map(f32::to_bits));
+        let indices = self.indices.iter().flatten().copied();
+        for word in vertices.chain(indices) {
+            for byte in word.to_le_bytes() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        hash
+    }
+}
+
+/// A closed triangle mesh.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConvexPart {
+    pub vertices: Vec<[f32; 3]>,
+    pub indices: Vec<[u32; 3]>,
+}
+
+/// Computed collider geometry in the mesh's local space. Unlike [`Collider`] it can be cached.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ColliderGeometry {
+    Trimesh {
+        vertices: Vec<[f32; 3]>,
+        indices: Vec<[u32; 3]>,
+    },
+    Convex(Vec<ConvexPart>),
+    Cuboid {
+        center: [f32; 3],
+        half_extents: [f32; 3],
+    },
+    Ball {
+        center: [f32; 3],
+        radius: f32,
+    },
+    /// Along the Y axis.
+    Capsule {
+        center: [f32; 3],
+        half_height: f32,
+        radius: f32,
+    },
+}
+
+impl ColliderGeometry {
+    pub fn compute(shape: ColliderShape, mesh: &MeshData) -> Option<Self> {
+        let points: Vec<_> = mesh.vertices.iter().map(|v| (*v).into()).collect();
+        let aabb = Aabb::enclosing(mesh.vertices.iter().copied())?;
+        let center = Vec3::from(aabb.center).to_array();
+        let half_extents = Vec3::from(aabb.half_extents);
+
+        let geometry = match shape {
+            ColliderShape::Trimesh => ColliderGeometry::Trimesh {
+                vertices: mesh.vertices.iter().map(|v| v.to_array()).collect(),
+                indices: mesh.indices.clone(),
+            },
+            ColliderShape::ConvexHull => {
+                let (vertices, indices) = convex_hull(&points);
+                ColliderGeometry::Convex(vec![ConvexPart::new(vertices, indices)])
+            }
+            ColliderShape::ConvexDecomposition => {
+                let decomposition = VHACD::decompose(&default(), &points, &mesh.indices, true);
+                let parts = decomposition
+                    .compute_exact_convex_hulls(&points, &mesh.indices)
+                    .into_iter()
+                    .map(|(vertices, indices)| ConvexPart::new(vertices, indices))
+                    .collect();
+                ColliderGeometry::Convex(parts)
+            }
+            ColliderShape::Cuboid => ColliderGeometry::Cuboid {
+                center,
+                half_extents: half_extents.to_array(),
+            },
+            ColliderShape::Ball => ColliderGeometry::Ball {
+                center,
+                radius: half_extents.max_element(),
+            },
+            ColliderShape::Capsule => {
+                let radius = half_extents.x.max(half_extents.z);
+                ColliderGeometry::Capsule {
+                    center,
+                    half_height: (half_extents.y - radius).max(0.0),
+                    radius,
+                }
+            }
+        };
+        Some(geometry)
+    }
+
+    pub fn to_collider(&self) -> Option<Collider> {
+        let (center, collider) = match self {
+            ColliderGeometry::Trimesh { vertices, indices } => {
+                let points = vertices
+                    .iter()
+                    .map(|v| Vec3::from_array(*v).into())
+                    .collect();
+                let shape = SharedShape::trimesh_with_flags(
+                    points,
+                    indices.clone(),
+                    TriMeshFlags::MERGE_DUPLICATE_VERTICES,
+                );
+                return Some(shape.into());
+            }
+            ColliderGeometry::Convex(parts) => {
+                let mut colliders: Vec<_> = parts
+                    .iter()
+                    .filter_map(|part| {
+                        let vertices = part.vertices.iter().copied().map(Vec3::from_array);
+                        Collider::convex_mesh(vertices.collect(), &part.indices)
+                    })
+                    .collect();
+                return match colliders.len() {
+                    0 => None,
+                    1 => colliders.pop(),
+                    _ => Some(Collider::compound(
+                        colliders
+                            .into_iter()
+                            .map(|collider| (Vec3::ZERO, Quat::IDENTITY, collider))
+                            .collect(),
+                    )),
+                };
+            }
+            ColliderGeometry::Cuboid {
+                center,
+                half_extents: [x, y, z],
+            } => (center, Collider::cuboid(*x, *y, *z)),
+            ColliderGeometry::Ball { center, radius } => (center, Collider::ball(*radius)),
+            ColliderGeometry::Capsule {
+                center,
+                half_height,
+                radius,
+            } => (center, Collider::capsule_y(*half_height, *radius)),
+        };
+
+        let center = Vec3::from_array(*center);
+        if center == Vec3::ZERO {
+            Some(collider)
+        } else {
+            Some(Collider::compound(vec![(center, Quat::IDENTITY, collider)]))
+        }
+    }
+}
+
+impl ConvexPart {
+    fn new(vertices: Vec<Point<Real>>, indices: Vec<[u32; 3]>) -> Self {
+        Self {
+            vertices: vertices.into_iter().map(|v| [v.x, v.y, v.z]).collect(),
+            indices,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ColliderCacheError {
+    Io(io::Error),
+    Malformed(bincode::Error),
+    Version { found: u32 },
+}
+
+impl fmt::Display for ColliderCacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColliderCacheError::Io(err) => write!(f, "could not access collider cache: {}", err),
+            ColliderCacheError::Malformed(err) => write!(f, "malformed collider cache: {}", err),
+            ColliderCacheError::Version { found } => write!(
+                f,
+                "collider cache version {} does not match {}",
+                found,
+                ColliderCache::VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ColliderCacheError {}
+
+impl From<io::Error> for ColliderCacheError {
+    fn from(err: io::Error) -> Self {
+        ColliderCacheError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for ColliderCacheError {
+    fn from(err: bincode::Error) -> Self {
+        ColliderCacheError::Malformed(err)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedCollider {
+    /// [`MeshData::fingerprint`] of the mesh the geometry was computed from.
+    pub fingerprint: u64,
+    pub geometry: ColliderGeometry,
+}
+
+/// Collider geometry computed ahead of time, keyed by mesh asset label and shape, so big maps
+/// don't have to be decomposed on every start. A mesh used by several nodes with different shapes
+/// has one entry per shape. Entries whose mesh changed are recomputed.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Resource)]
+pub struct ColliderCache {
+    version: u32,
+    pub colliders: HashMap<(String, ColliderShape), CachedCollider>,
+}
+
+impl ColliderCache {
+    pub const VERSION: u32 = 2;
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, ColliderCacheError> {
+        let cache: Self = bincode::deserialize(&fs::read(path)?)?;
+        if cache.version != Self::VERSION {
+            return Err(ColliderCacheError::Version {
+                found: cache.version,
+            });
+        }
+        Ok(cache)
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), ColliderCacheError> {
+        let cache = Self {
+            version: Self::VERSION,
+            colliders: self.colliders.clone(),
+        };
+        fs::write(path, bincode::serialize(&cache)?)?;
+        Ok(())
+    }
+
+    /// The `shape` geometry for the mesh at `path`, computed and cached unless an up to date
+    /// entry exists.
+    pub fn get_or_compute(
+        &mut self,
+        path: &str,
+        shape: ColliderShape,
+        mesh: &MeshData,
+    ) -> Option<&ColliderGeometry> {
+        let key = (path.to_string(), shape);
+        let fingerprint = mesh.fingerprint();
+        let fresh = self
+            .colliders
+            .get(&key)
+            .is_some_and(|cached| cached.fingerprint == fingerprint);
+        if !fresh {
+            let geometry = ColliderGeometry::compute(shape, mesh)?;
+            self.colliders.insert(
+                key.clone(),
+                CachedCollider {
+                    fingerprint,
+                    geometry,
+                },
+            );
+        }
+        self.colliders.get(&key).map(|cached| &cached.geometry)
+    }
+}
@@ -0,0 +1,416 @@
+pub mod collider;
+
+pub use collider::{ColliderCache, ColliderGeometry, ColliderShape};
+
+use crate::{controller::ControllerSettings, simulation::SimulationSet};
+use bevy::{gltf::*, hierarchy::HierarchyQueryExt, prelude::*};
+use bevy_asset_loader::prelude::*;
+use bevy_rapier3d::prelude::*;
+use collider::MeshData;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Map loaded when none is configured.
+pub const DEFAULT_MAP: &str = "playground.glb";
+
+/// Name of the node whose extras hold the [`MapSettings`].
+pub const SETTINGS_NODE: &str = "map_settings";
+
+/// Loads the map at `path` as the `map` dynamic asset of [`WorldAssets`] and builds its colliders,
+/// volumes and settings once it is loaded. The app still has to load [`WorldAssets`] in one of its
+/// loading states.
+pub struct MapPlugin {
+    pub path: String,
+    /// Collider cache written by the `colliders` binary. Colliders missing from it are computed on
+    /// load.
+    pub collider_cache: Option<PathBuf>,
+}
+
+impl Default for MapPlugin {
+    fn default() -> Self {
+        Self {
+            path: DEFAULT_MAP.to_string(),
+            collider_cache: None,
+        }
+    }
+}
+
+impl Plugin for MapPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<DynamicAssets>();
+        register_map(&mut app.world.resource_mut::<DynamicAssets>(), &self.path);
+
+        let cache = match &self.collider_cache {
+            Some(path) => ColliderCache::read(path).unwrap_or_else(|err| {
+                println!("Not using collider cache {}: {}", path.display(), err);
+                ColliderCache::default()
+            }),
+            None => ColliderCache::default(),
+        };
+
+        app.init_resource::<MapSettings>()
+            .insert_resource(cache)
+            .add_event::<MapLoaded>()
+            .add_systems(
+                Update,
+                (
+                    load_map.run_if(resource_added::<WorldAssets>()),
+                    hide_collision_only_nodes,
+                ),
+            )
+            .add_systems(FixedUpdate, apply_map_gravity.in_set(SimulationSet::Input));
+    }
+}
+
+/// Points the `map` dynamic asset of [`WorldAssets`] at `path`. Only affects collections loaded
+/// afterwards.
+pub fn register_map(assets: &mut DynamicAssets, path: &str) {
+    assets.register_asset(
+        "map",
+        Box::new(StandardDynamicAsset::File {
+            path: path.to_string(),
+        }),
+    );
+}
+
+#[derive(AssetCollection, Resource)]
+pub struct WorldAssets {
+    #[asset(key = "map")]
+    pub map: Handle<Gltf>,
+}
+
+/// Sent once the colliders of the map are spawned and its [`MapSettings`] inserted.
+#[derive(Debug, Clone, Event)]
+pub struct MapLoaded {
+    /// Scene rendering the map.
+    pub scene: Handle<Scene>,
+    /// Spawn points sorted by name, in world space.
+    pub spawn_points: Vec<SpawnPoint>,
+}
+
+/// Marks the entity the map scene of [`MapLoaded`] is spawned under. Only its descendants are
+/// treated as map nodes when the scene is rendered.
+#[derive(Debug, Default, Component)]
+pub struct MapScene;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpawnPoint {
+    pub name: String,
+    pub position: Vec3,
+    /// Team allowed to spawn here, from the node's `team` extra. `None` for any team.
+    pub team: Option<String>,
+}
+
+/// Gameplay settings of a map, read from the extras of its `map_settings` node.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Resource)]
+#[serde(default)]
+pub struct MapSettings {
+    /// Overrides the gravity of every character controller and projectile.
+    pub gravity: Option<f32>,
+    /// Round length in seconds.
+    pub time_limit: Option<f32>,
+}
+
+/// Trigger volume of a map, a sensor that players can walk into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Component)]
+#[serde(rename_all = "snake_case")]
+pub enum Volume {
+    Kill,
+    Capture,
+    Ladder,
+    Water,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionMode {
+    /// Rendered and collidable.
+    Solid,
+    /// Collidable but never rendered, e.g. invisible walls.
+    Only,
+    /// Rendered without a collider, e.g. foliage.
+    None,
+}
+
+/// Custom properties of a glTF node.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct NodeExtras {
+    pub collision: Option<CollisionMode>,
+    pub volume: Option<Volume>,
+    pub collider: Option<ColliderShape>,
+    /// Team allowed to use a spawn point.
+    pub team: Option<String>,
+}
+
+impl NodeExtras {
+    /// Parses the extras JSON, treating missing or malformed extras as empty.
+    pub fn parse(extras: Option<&GltfExtras>) -> Self {
+        extras
+            .and_then(|extras| serde_json::from_str(&extras.value).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// What a node of the map is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapNodeKind {
+    Solid,
+    CollisionOnly,
+    RenderOnly,
+    Volume(Volume),
+    Spawn,
+    Settings,
+}
+
+impl MapNodeKind {
+    /// Classifies a node by its extras or, failing that, by its name prefix.
+    pub fn of(name: Option<&str>, extras: &NodeExtras) -> Self {
+        if let Some(volume) = extras.volume {
+            return MapNodeKind::Volume(volume);
+        }
+        match extras.collision {
+            Some(CollisionMode::Solid) => return MapNodeKind::Solid,
+            Some(CollisionMode::Only) => return MapNodeKind::CollisionOnly,
+            Some(CollisionMode::None) => return MapNodeKind::RenderOnly,
+            None => {}
+        }
+
+        let Some(name) = name else {
+            return MapNodeKind::Solid;
+        };
+        let prefixes = [
+            ("col_", MapNodeKind::CollisionOnly),
+            ("render_", MapNodeKind::RenderOnly),
+            ("kill_", MapNodeKind::Volume(Volume::Kill)),
+            ("capture_", MapNodeKind::Volume(Volume::Capture)),
+            ("ladder_", MapNodeKind::Volume(Volume::Ladder)),
+            ("water_", MapNodeKind::Volume(Volume::Water)),
+            ("spawn_", MapNodeKind::Spawn),
+        ];
+
+        if name == SETTINGS_NODE {
+            MapNodeKind::Settings
+        } else {
+            prefixes
+                .into_iter()
+                .find(|(prefix, _)| name.starts_with(prefix))
+                .map(|(_, kind)| kind)
+                .unwrap_or(MapNodeKind::Solid)
+        }
+    }
+}
+
+/// Spawns the colliders and trigger volumes of the map once its [`WorldAssets`] are loaded, then
+/// sends [`MapLoaded`].
+fn load_map(
+    mut commands: Commands,
+    world_assets: Res<WorldAssets>,
+    gltf_assets: Res<Assets<Gltf>>,
+    scene_assets: Res<Assets<Scene>>,
+    mesh_assets: Res<Assets<Mesh>>,
+    mut cache: ResMut<ColliderCache>,
+    mut loaded: EventWriter<MapLoaded>,
+) {
+    let gltf = gltf_assets.get(&world_assets.map).unwrap();
+    let Some(scene) = gltf
+        .default_scene
+        .clone()
+        .or_else(|| gltf.scenes.first().cloned())
+    else {
+        println!("The map has no scene");
+        return;
+    };
+    let scene_world = &scene_assets.get(&scene).unwrap().world;
+
+    let mut builder = MapBuilder {
+        commands: &mut commands,
+        scene: scene_world,
+        meshes: &mesh_assets,
+        cache: &mut cache,
+        settings: MapSettings::default(),
+        spawn_points: Vec::new(),
+    };
+    for root in scene_world
+        .iter_entities()
+        .filter(|entity| !entity.contains::<Parent>())
+    {
+        builder.visit(root.id(), &GlobalTransform::IDENTITY, MapNode::default());
+    }
+
+    let MapBuilder {
+        settings,
+        mut spawn_points,
+        ..
+    } = builder;
+    spawn_points.sort_by(|a, b| a.name.cmp(&b.name));
+
+    commands.insert_resource(settings);
+    loaded.send(MapLoaded {
+        scene,
+        spawn_points,
+    });
+}
+
+/// Walks the map's scene, which holds the same node hierarchy the client renders.
+struct MapBuilder<'w, 's, 'a> {
+    commands: &'a mut Commands<'w, 's>,
+    scene: &'a World,
+    meshes: &'a Assets<Mesh>,
+    cache: &'a mut ColliderCache,
+    settings: MapSettings,
+    spawn_points: Vec<SpawnPoint>,
+}
+
+/// The closest glTF node above an entity of the map's scene. Mesh primitives are its children.
+#[derive(Debug, Clone, Copy)]
+struct MapNode<'a> {
+    name: Option<&'a str>,
+    kind: MapNodeKind,
+    shape: Option<ColliderShape>,
+}
+
+impl Default for MapNode<'_> {
+    fn default() -> Self {
+        Self {
+            name: None,
+            kind: MapNodeKind::Solid,
+            shape: None,
+        }
+    }
+}
+
+impl<'a> MapBuilder<'_, '_, 'a> {
+    /// Visits `entity` and its descendants.
+    fn visit(&mut self, entity: Entity, parent: &GlobalTransform, mut node: MapNode<'a>) {
+        let scene = self.scene;
+        let transform = *parent * scene.get::<Transform>(entity).copied().unwrap_or_default();
+
+        if let Some(mesh) = scene.get::<Handle<Mesh>>(entity) {
+            self.spawn_collider(mesh, &transform, node);
+        } else {
+            let name = scene.get::<Name>(entity).map(Name::as_str);
+            let extras = scene.get::<GltfExtras>(entity);
+            let node_extras = NodeExtras::parse(extras);
+            let kind = MapNodeKind::of(name, &node_extras);
+
+            match kind {
+                MapNodeKind::Settings => {
+                    if let Some(extras) = extras {
+                        match serde_json::from_str(&extras.value) {
+                            Ok(settings) => self.settings = settings,
+                            Err(err) => println!("Invalid map settings: {}", err),
+                        }
+                    }
+                }
+                MapNodeKind::Spawn => self.spawn_points.push(SpawnPoint {
+                    name: name.unwrap_or_default().to_string(),
+                    position: transform.translation(),
+                    team: node_extras.team.clone(),
+                }),
+                _ => {}
+            }
+            node = MapNode {
+                name,
+                kind,
+                shape: node_extras
+                    .collider
+                    .or_else(|| name.and_then(ColliderShape::from_name)),
+            };
+        }
+
+        if let Some(children) = scene.get::<Children>(entity) {
+            for child in children {
+                self.visit(*child, &transform, node);
+            }
+        }
+    }
+
+    fn spawn_collider(
+        &mut self,
+        mesh: &Handle<Mesh>,
+        transform: &GlobalTransform,
+        MapNode { name, kind, shape }: MapNode,
+    ) {
+        let (default_shape, volume) = match kind {
+            MapNodeKind::Solid | MapNodeKind::CollisionOnly => (ColliderShape::Trimesh, None),
+            // Volumes need an inside to detect players in, which a trimesh lacks.
+            MapNodeKind::Volume(volume) => (ColliderShape::ConvexHull, Some(volume)),
+            MapNodeKind::RenderOnly | MapNodeKind::Spawn | MapNodeKind::Settings => return,
+        };
+        let shape = shape.unwrap_or(default_shape);
+
+        let Some(collider) = self.collider(mesh, shape) else {
+            println!(
+                "Could not build a {:?} collider for {}",
+                shape,
+                name.unwrap_or("a node")
+            );
+            return;
+        };
+
+        let mut entity = self.commands.spawn((
+            collider,
+            RigidBody::Fixed,
+            TransformBundle::from_transform(transform.compute_transform()),
+        ));
+        if let Some(name) = name {
+            entity.insert(Name::new(name.to_string()));
+        }
+        if let Some(volume) = volume {
+            entity.insert((volume, Sensor));
+        }
+    }
+
+    /// Builds the collider from the cache when it holds this mesh, by its asset path.
+    fn collider(&mut self, mesh: &Handle<Mesh>, shape: ColliderShape) -> Option<Collider> {
+        let data = MeshData::from_mesh(self.meshes.get(mesh)?)?;
+        match mesh.path() {
+            Some(path) => self
+                .cache
+                .get_or_compute(&path.to_string(), shape, &data)?
+                .to_collider(),
+            None => ColliderGeometry::compute(shape, &data)?.to_collider(),
+        }
+    }
+}
+
+/// Hides the rendered meshes of collision-only nodes once the map scene is spawned. Other named
+/// entities, like players, are left alone whatever their name.
+fn hide_collision_only_nodes(
+    mut query: Query<(Entity, &Name, Option<&GltfExtras>, &mut Visibility), Added<Name>>,
+    parents: Query<&Parent>,
+    map_scenes: Query<(), With<MapScene>>,
+) {
+    for (entity, name, extras, mut visibility) in query.iter_mut() {
+        if !parents
+            .iter_ancestors(entity)
+            .any(|ancestor| map_scenes.contains(ancestor))
+        {
+            continue;
+        }
+
+        let kind = MapNodeKind::of(Some(name.as_str()), &NodeExtras::parse(extras));
+        if matches!(kind, MapNodeKind::CollisionOnly | MapNodeKind::Volume(_)) {
+            *visibility = Visibility::Hidden;
+        }
+    }
+}
+
+/// Applies the map's gravity to character controllers.
+fn apply_map_gravity(
+    settings: Res<MapSettings>,
+    mut query: Query<&mut ControllerSettings>,
+    added: Query<(), Added<ControllerSettings>>,
+) {
+    let Some(gravity) = settings.gravity else {
+        return;
+    };
+    if !settings.is_changed() && added.is_empty() {
+        return;
+    }
+
+    for mut controller_settings in query.iter_mut() {
+        controller_settings.gravity = gravity;
+    }
+}
@@ -0,0 +1,235 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Gravity pulling projectiles down, unless the map overrides it.
+pub const GRAVITY: f32 = 9.81;
+/// Height above a player's feet that projectiles are launched from, about the eyes.
+pub const LAUNCH_HEIGHT: f32 = 1.6;
+/// Seconds a projectile flies before it is removed without hitting anything.
+pub const PROJECTILE_LIFETIME: f32 = 10.0;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RangedWeaponKind {
+    #[default]
+    Bow,
+    Crossbow,
+    ThrowingAxe,
+}
+
+/// Ballistics of a ranged weapon and its projectile, the same on the client and the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RangedStats {
+    /// Launch speed in meters per second when loosed without drawing.
+    pub min_speed: f32,
+    /// Launch speed at full draw.
+    pub max_speed: f32,
+    /// Seconds to reach full draw, zero for weapons that are always fully drawn.
+    pub draw_time: f32,
+    /// Seconds after loosing before the weapon can be drawn again.
+    pub reload: f32,
+    /// Quadratic drag, slowing the projectile by `drag * speed²` meters per second each second.
+    pub drag: f32,
+    /// Radius of the ball swept along the flight path.
+    pub radius: f32,
+    /// Damage of a hit at full launch speed.
+    pub damage: f32,
+    /// Whether the projectile sticks into the map where it lands.
+    pub sticks: bool,
+}
+
+impl RangedWeaponKind {
+    pub fn stats(self) -> RangedStats {
+        match self {
+            RangedWeaponKind::Bow => RangedStats {
+                min_speed: 20.0,
+                max_speed: 55.0,
+                draw_time: 1.0,
+                reload: 0.3,
+                drag: 0.001,
+                radius: 0.03,
+                damage: 70.0,
+                sticks: true,
+            },
+            RangedWeaponKind::Crossbow => RangedStats {
+                min_speed: 70.0,
+                max_speed: 70.0,
+                draw_time: 0.0,
+                reload: 2.5,
+                drag: 0.0008,
+                radius: 0.03,
+                damage: 90.0,
+                sticks: true,
+            },
+            RangedWeaponKind::ThrowingAxe => RangedStats {
+                min_speed: 10.0,
+                max_speed: 18.0,
+                draw_time: 0.4,
+                reload: 0.8,
+                drag: 0.004,
+                radius: 0.15,
+                damage: 60.0,
+                sticks: false,
+            },
+        }
+    }
+}
+
+impl RangedStats {
+    /// Launch speed after drawing for `drawn` seconds.
+    pub fn launch_speed(&self, drawn: f32) -> f32 {
+        let strength = if self.draw_time > 0.0 {
+            (drawn / self.draw_time).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+        self.min_speed + (self.max_speed - self.min_speed) * strength
+    }
+
+    /// Damage of a hit at `speed`. Slow projectiles, drawn weakly or far into their flight, deal
+    /// less.
+    pub fn damage_at(&self, speed: f32) -> f32 {
+        self.damage * (speed / self.max_speed).min(1.0)
+    }
+}
+
+/// Network id of a projectile, only used by projectile messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Component)]
+pub struct ProjectileId(pub u32);
+
+/// Position and velocity of a projectile in flight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Flight {
+    pub position: Vec3,
+    pub velocity: Vec3,
+}
+
+impl Flight {
+    /// Advances the flight by `dt` seconds under gravity and drag. Clients simulate projectiles
+    /// locally with the same step the server hit-tests with.
+    pub fn step(&mut self, stats: &RangedStats, gravity: f32, dt: f32) {
+        let drag = -self.velocity * self.velocity.length() * stats.drag;
+        self.velocity += (Vec3::NEG_Y * gravity + drag) * dt;
+        self.position += self.velocity * dt;
+    }
+}
+
+/// Ranged weapon of a player, drawn and loosed separately from the melee weapon.
+#[derive(Debug, Default, Component)]
+pub struct RangedWeapon {
+    pub kind: RangedWeaponKind,
+    /// Seconds the weapon has been drawn, `None` when it is not.
+    pub drawn: Option<f32>,
+    /// Seconds left before the weapon can be drawn again.
+    pub reload: f32,
+}
+
+impl RangedWeapon {
+    /// Starts drawing `kind` unless the weapon is already drawn or reloading.
+    pub fn draw(&mut self, kind: RangedWeaponKind) -> bool {
+        if self.drawn.is_some() || self.reload > 0.0 {
+            return false;
+        }
+
+        self.kind = kind;
+        self.drawn = Some(0.0);
+        true
+    }
+
+    /// Looses a drawn weapon, returning the launch speed of its projectile.
+    pub fn loose(&mut self) -> Option<f32> {
+        let drawn = self.drawn.take()?;
+        let stats = self.kind.stats();
+        self.reload = stats.reload;
+        Some(stats.launch_speed(drawn))
+    }
+
+    /// Advances draw and reload timers by `dt` seconds.
+    pub fn tick(&mut self, dt: f32) {
+        self.reload = (self.reload - dt).max(0.0);
+        if let Some(drawn) = self.drawn.as_mut() {
+            *drawn += dt;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn without_drag() -> RangedStats {
+        RangedStats {
+            drag: 0.0,
+            ..RangedWeaponKind::Bow.stats()
+        }
+    }
+
+    #[test]
+    fn step_applies_gravity_before_moving() {
+        let mut flight = Flight {
+            position: Vec3::ZERO,
+            velocity: Vec3::X * 10.0,
+        };
+
+        flight.step(&without_drag(), GRAVITY, 0.1);
+
+        assert!(flight
+            .velocity
+            .abs_diff_eq(Vec3::new(10.0, -0.981, 0.0), 1e-5));
+        assert!(flight
+            .position
+            .abs_diff_eq(Vec3::new(1.0, -0.0981, 0.0), 1e-5));
+    }
+
+    #[test]
+    fn free_fall_follows_gravity() {
+        let mut flight = Flight {
+            position: Vec3::ZERO,
+            velocity: Vec3::ZERO,
+        };
+
+        let dt = 1.0 / 64.0;
+        for _ in 0..64 {
+            flight.step(&without_drag(), GRAVITY, dt);
+        }
+
+        assert!((flight.velocity.y + GRAVITY).abs() < 1e-3);
+        // Semi-implicit Euler overshoots the exact fall of g/2 by g·dt/2.
+        assert!((flight.position.y + GRAVITY / 2.0 + GRAVITY * dt / 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn drag_is_quadratic_in_speed() {
+        let stats = RangedStats {
+            drag: 0.01,
+            ..without_drag()
+        };
+        let mut flight = Flight {
+            position: Vec3::ZERO,
+            velocity: Vec3::new(30.0, 0.0, 40.0),
+        };
+
+        flight.step(&stats, 0.0, 0.1);
+
+        // Speed 50 loses drag · 50² · dt = 2.5 along its direction.
+        assert!(flight
+            .velocity
+            .abs_diff_eq(Vec3::new(28.5, 0.0, 38.0), 1e-4));
+        assert!(flight.position.abs_diff_eq(Vec3::new(2.85, 0.0, 3.8), 1e-4));
+    }
+
+    #[test]
+    fn falling_approaches_terminal_velocity() {
+        let stats = RangedWeaponKind::ThrowingAxe.stats();
+        let mut flight = Flight {
+            position: Vec3::ZERO,
+            velocity: Vec3::ZERO,
+        };
+
+        for _ in 0..60 * 120 {
+            flight.step(&stats, GRAVITY, 1.0 / 60.0);
+        }
+
+        let terminal = (GRAVITY / stats.drag).sqrt();
+        assert!((flight.velocity.y + terminal).abs() < 0.1);
+    }
+}
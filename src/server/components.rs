@@ -1 +1,125 @@
+use crate::{
+    controller::FpsControllerInput,
+    is_newer_sequence,
+    projectile::{Flight, ProjectileId, RangedWeaponKind},
+};
+use bevy::prelude::*;
+use std::collections::VecDeque;
 
+/// Inputs received from the owning client, consumed one per tick.
+#[derive(Debug, Default, Component)]
+pub struct PlayerInputs {
+    pub queue: VecDeque<(u32, FpsControllerInput)>,
+    pub last_received: Option<u32>,
+    pub last_processed: Option<u32>,
+}
+
+impl PlayerInputs {
+    /// Inputs buffered beyond this are dropped, so a burst of late packets cannot add latency.
+    pub const MAX_QUEUED: usize = 8;
+
+    pub fn push(&mut self, sequence: u32, input: FpsControllerInput) {
+        if self
+            .last_received
+            .is_some_and(|last| !is_newer_sequence(sequence, last))
+        {
+            return;
+        }
+
+        self.last_received = Some(sequence);
+        self.queue.push_back((sequence, input));
+        while self.queue.len() > Self::MAX_QUEUED {
+            self.queue.pop_front();
+        }
+    }
+}
+
+/// Hit points of a player, who dies when they reach zero.
+#[derive(Debug, Clone, Copy, Component)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Health {
+    pub const DEFAULT_MAX: f32 = 100.0;
+
+    pub fn is_dead(&self) -> bool {
+        self.current <= 0.0
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            current: Self::DEFAULT_MAX,
+            max: Self::DEFAULT_MAX,
+        }
+    }
+}
+
+/// Takes a share of the damage dealt to a player until its points run out.
+#[derive(Debug, Clone, Copy, Component)]
+pub struct Armor {
+    pub points: f32,
+    /// Fraction of each hit absorbed by the armor.
+    pub absorption: f32,
+}
+
+impl Armor {
+    pub const ABSORPTION: f32 = 0.5;
+
+    pub fn new(points: f32) -> Self {
+        Self {
+            points,
+            absorption: Self::ABSORPTION,
+        }
+    }
+
+    /// Absorbs what it can of `damage`, returning the damage left for the player's health.
+    pub fn absorb(&mut self, damage: f32) -> f32 {
+        let absorbed = (damage * self.absorption).min(self.points);
+        self.points -= absorbed;
+        damage - absorbed
+    }
+}
+
+/// A player whose health ran out, respawned once `respawn` finishes. Dead players take no damage
+/// and ignore their inputs.
+#[derive(Debug, Component)]
+pub struct Dead {
+    pub respawn: Timer,
+}
+
+/// Blocks all damage to a freshly respawned player until the timer finishes.
+#[derive(Debug, Component)]
+pub struct SpawnProtection(pub Timer);
+
+/// A projectile in flight, hit-tested along its path every tick.
+#[derive(Debug, Component)]
+pub struct Projectile {
+    pub id: ProjectileId,
+    pub kind: RangedWeaponKind,
+    /// Player who launched it, whom it never hits.
+    pub owner: Entity,
+    pub flight: Flight,
+    /// Seconds since launch.
+    pub age: f32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inputs_keep_arriving_after_the_sequence_wraps() {
+        let mut inputs = PlayerInputs::default();
+        inputs.push(u32::MAX, FpsControllerInput::default());
+        inputs.push(0, FpsControllerInput::default());
+        inputs.push(u32::MAX, FpsControllerInput::default());
+
+        let sequences: Vec<u32> = inputs.queue.iter().map(|(sequence, _)| *sequence).collect();
+        assert_eq!(sequences, [u32::MAX, 0]);
+        assert_eq!(inputs.last_received, Some(0));
+    }
+}
@@ -0,0 +1,296 @@
+use crate::{
+    auth::parse_private_key,
+    combat::WeaponKind,
+    config::{read_config_file, ConfigError},
+    map::DEFAULT_MAP,
+    PROTOCOL_ID,
+};
+use bevy::prelude::*;
+use bevy_renet::renet::transport::ServerAuthentication;
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::{fmt, io, net::SocketAddr, path::PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+pub enum AuthenticationMode {
+    /// Only accept clients holding a connect token signed with the server's private key.
+    #[default]
+    Secure,
+    /// Accept any client id without a connect token. Only meant for local development.
+    Unsecure,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+pub enum SpawnStrategy {
+    Random,
+    /// Cycles through the spawn points in name order.
+    RoundRobin,
+    /// Picks the spawn point farthest from every other player.
+    #[default]
+    FarthestFromEnemies,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Resource)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Address the UDP socket binds to.
+    pub bind_addr: SocketAddr,
+    /// Address clients use to reach the server, e.g. the host address behind a container.
+    /// Defaults to the bind address.
+    pub public_addr: Option<SocketAddr>,
+    /// Connection slots. Should exceed `max_players` so late clients can be told the server is
+    /// full instead of timing out.
+    pub max_clients: usize,
+    /// Clients admitted by the handshake.
+    pub max_players: usize,
+    /// Client ids rejected during the handshake.
+    pub banned_clients: Vec<u64>,
+    /// Simulation ticks per second.
+    pub tick_rate: f64,
+    /// Snapshots sent to every client per second.
+    pub snapshot_rate: f64,
+    pub protocol_id: u64,
+    /// Asset path of the map glTF.
+    pub map: String,
+    /// Colliders precomputed by the `colliders` binary for the map.
+    pub collider_cache: Option<PathBuf>,
+    pub spawn_strategy: SpawnStrategy,
+    /// Seconds a dead player waits before respawning.
+    pub respawn_delay: f32,
+    /// Seconds a respawned player cannot be damaged.
+    pub spawn_protection: f32,
+    /// Armor points players spawn and respawn with.
+    pub starting_armor: f32,
+    /// Melee weapons players may spawn with. Players asking for another get the first one.
+    pub melee_weapons: Vec<WeaponKind>,
+    pub authentication: AuthenticationMode,
+    /// Hex encoded key used to validate connect tokens in secure mode.
+    pub private_key: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: "127.0.0.1:5000".parse().unwrap(),
+            public_addr: None,
+            max_clients: 64,
+            max_players: 32,
+            banned_clients: Vec::new(),
+            tick_rate: 60.0,
+            snapshot_rate: 30.0,
+            protocol_id: PROTOCOL_ID,
+            map: DEFAULT_MAP.to_string(),
+            collider_cache: None,
+            spawn_strategy: SpawnStrategy::FarthestFromEnemies,
+            respawn_delay: 3.0,
+            spawn_protection: 2.0,
+            starting_armor: 50.0,
+            melee_weapons: vec![WeaponKind::Sword, WeaponKind::Axe, WeaponKind::Mace],
+            authentication: AuthenticationMode::Secure,
+            private_key: None,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(about = "Medieval Call of Duty dedicated server")]
+struct ServerArgs {
+    /// RON config file, overridden by any other argument or environment variable.
+    #[arg(long, env = "MCOD_SERVER_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "MCOD_BIND_ADDR")]
+    bind_addr: Option<SocketAddr>,
+    #[arg(long, env = "MCOD_PUBLIC_ADDR")]
+    public_addr: Option<SocketAddr>,
+    #[arg(long, env = "MCOD_MAX_CLIENTS")]
+    max_clients: Option<usize>,
+    #[arg(long, env = "MCOD_MAX_PLAYERS")]
+    max_players: Option<usize>,
+    #[arg(long, env = "MCOD_TICK_RATE")]
+    tick_rate: Option<f64>,
+    #[arg(long, env = "MCOD_SNAPSHOT_RATE")]
+    snapshot_rate: Option<f64>,
+    #[arg(long, env = "MCOD_PROTOCOL_ID")]
+    protocol_id: Option<u64>,
+    #[arg(long, env = "MCOD_MAP")]
+    map: Option<String>,
+    #[arg(long, env = "MCOD_COLLIDER_CACHE")]
+    collider_cache: Option<PathBuf>,
+    #[arg(long, env = "MCOD_SPAWN_STRATEGY")]
+    spawn_strategy: Option<SpawnStrategy>,
+    #[arg(long, env = "MCOD_RESPAWN_DELAY")]
+    respawn_delay: Option<f32>,
+    #[arg(long, env = "MCOD_SPAWN_PROTECTION")]
+    spawn_protection: Option<f32>,
+    #[arg(long, env = "MCOD_STARTING_ARMOR")]
+    starting_armor: Option<f32>,
+    /// Comma separated, e.g. `sword,axe`.
+    #[arg(long, env = "MCOD_MELEE_WEAPONS", value_delimiter = ',')]
+    melee_weapons: Option<Vec<WeaponKind>>,
+    #[arg(long, env = "MCOD_AUTHENTICATION")]
+    authentication: Option<AuthenticationMode>,
+    #[arg(long, env = "MCOD_PRIVATE_KEY", hide_env_values = true)]
+    private_key: Option<String>,
+}
+
+impl ServerConfig {
+    /// Builds the config from the command line, the environment and the optional config file,
+    /// in that order of precedence.
+    pub fn from_args() -> Result<Self, ConfigError> {
+        let args = ServerArgs::parse();
+
+        let mut config = match &args.config {
+            Some(path) => read_config_file(path)?,
+            None => ServerConfig::default(),
+        };
+
+        if let Some(bind_addr) = args.bind_addr {
+            config.bind_addr = bind_addr;
+        }
+        if let Some(public_addr) = args.public_addr {
+            config.public_addr = Some(public_addr);
+        }
+        if let Some(max_clients) = args.max_clients {
+            config.max_clients = max_clients;
+        }
+        if let Some(max_players) = args.max_players {
+            config.max_players = max_players;
+        }
+        if let Some(tick_rate) = args.tick_rate {
+            config.tick_rate = tick_rate;
+        }
+        if let Some(snapshot_rate) = args.snapshot_rate {
+            config.snapshot_rate = snapshot_rate;
+        }
+        if let Some(protocol_id) = args.protocol_id {
+            config.protocol_id = protocol_id;
+        }
+        if let Some(map) = args.map {
+            config.map = map;
+        }
+        if let Some(collider_cache) = args.collider_cache {
+            config.collider_cache = Some(collider_cache);
+        }
+        if let Some(spawn_strategy) = args.spawn_strategy {
+            config.spawn_strategy = spawn_strategy;
+        }
+        if let Some(respawn_delay) = args.respawn_delay {
+            config.respawn_delay = respawn_delay;
+        }
+        if let Some(spawn_protection) = args.spawn_protection {
+            config.spawn_protection = spawn_protection;
+        }
+        if let Some(starting_armor) = args.starting_armor {
+            config.starting_armor = starting_armor;
+        }
+        if let Some(melee_weapons) = args.melee_weapons {
+            config.melee_weapons = melee_weapons;
+        }
+        if let Some(authentication) = args.authentication {
+            config.authentication = authentication;
+        }
+        if let Some(private_key) = args.private_key {
+            config.private_key = Some(private_key);
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Rejects values the server cannot run with, wherever they came from.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (name, rate) in [
+            ("tick_rate", self.tick_rate),
+            ("snapshot_rate", self.snapshot_rate),
+        ] {
+            if !rate.is_finite() || rate <= 0.0 {
+                return Err(ConfigError::Invalid(format!(
+                    "{} must be a positive number per second, got {}",
+                    name, rate
+                )));
+            }
+        }
+        for (name, seconds) in [
+            ("respawn_delay", self.respawn_delay),
+            ("spawn_protection", self.spawn_protection),
+        ] {
+            if !seconds.is_finite() || seconds < 0.0 {
+                return Err(ConfigError::Invalid(format!(
+                    "{} must be a non-negative number of seconds, got {}",
+                    name, seconds
+                )));
+            }
+        }
+        if !self.starting_armor.is_finite() || self.starting_armor < 0.0 {
+            return Err(ConfigError::Invalid(format!(
+                "starting_armor must be a non-negative number of points, got {}",
+                self.starting_armor
+            )));
+        }
+        if self.melee_weapons.is_empty() {
+            return Err(ConfigError::Invalid(
+                "melee_weapons must allow at least one weapon".to_string(),
+            ));
+        }
+        self.server_authentication()?;
+
+        Ok(())
+    }
+
+    /// `weapon` if players may choose it, otherwise the first allowed weapon.
+    pub fn melee_weapon(&self, weapon: WeaponKind) -> WeaponKind {
+        if self.melee_weapons.contains(&weapon) {
+            weapon
+        } else {
+            self.melee_weapons[0]
+        }
+    }
+
+    pub fn public_addr(&self) -> SocketAddr {
+        self.public_addr.unwrap_or(self.bind_addr)
+    }
+
+    pub fn server_authentication(&self) -> Result<ServerAuthentication, ConfigError> {
+        match self.authentication {
+            AuthenticationMode::Secure => {
+                let private_key = self.private_key.as_deref().ok_or_else(|| {
+                    ConfigError::Invalid(
+                        "secure authentication needs a private key, pass --private-key or \
+                         --authentication unsecure"
+                            .to_string(),
+                    )
+                })?;
+                let private_key = parse_private_key(private_key)
+                    .map_err(|err| ConfigError::Invalid(err.to_string()))?;
+
+                Ok(ServerAuthentication::Secure { private_key })
+            }
+            AuthenticationMode::Unsecure => Ok(ServerAuthentication::Unsecure),
+        }
+    }
+}
+
+/// Why the server could not start.
+#[derive(Debug)]
+pub enum ServerStartError {
+    Config(ConfigError),
+    Bind(SocketAddr, io::Error),
+}
+
+impl fmt::Display for ServerStartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerStartError::Config(err) => write!(f, "{}", err),
+            ServerStartError::Bind(addr, err) => write!(f, "could not bind {}: {}", addr, err),
+        }
+    }
+}
+
+impl std::error::Error for ServerStartError {}
+
+impl From<ConfigError> for ServerStartError {
+    fn from(err: ConfigError) -> Self {
+        ServerStartError::Config(err)
+    }
+}
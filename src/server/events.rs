@@ -0,0 +1,43 @@
+use crate::DamageType;
+use bevy::prelude::*;
+
+/// Part of a player's body a hit landed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HitLocation {
+    Head,
+    Torso,
+    Legs,
+}
+
+impl HitLocation {
+    pub fn multiplier(self) -> f32 {
+        match self {
+            HitLocation::Head => 2.0,
+            HitLocation::Torso => 1.0,
+            HitLocation::Legs => 0.75,
+        }
+    }
+
+    /// Where a hit `height` above the player's feet lands.
+    pub fn from_height(height: f32) -> Self {
+        if height >= 1.6 {
+            HitLocation::Head
+        } else if height >= 0.9 {
+            HitLocation::Torso
+        } else {
+            HitLocation::Legs
+        }
+    }
+}
+
+/// Damage dealt to a player, applied to its [`Armor`](super::components::Armor) and
+/// [`Health`](super::components::Health) by the server.
+#[derive(Debug, Clone, Event)]
+pub struct DamageEvent {
+    pub target: Entity,
+    /// Player who dealt the damage, if any.
+    pub source: Option<Entity>,
+    pub amount: f32,
+    pub damage_type: DamageType,
+    pub location: HitLocation,
+}
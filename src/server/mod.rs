@@ -1,25 +1,37 @@
 mod components;
+mod config;
+mod events;
 mod resources;
 mod systems;
 
 use bevy::{app::ScheduleRunnerPlugin, prelude::*, winit::WinitPlugin};
 use bevy_asset_loader::prelude::*;
+use bevy_rapier3d::prelude::PhysicsSet;
 use bevy_renet::{
     renet::{
-        transport::{NetcodeServerTransport, ServerAuthentication, ServerConfig},
+        transport::{NetcodeServerTransport, ServerConfig as NetcodeServerConfig},
         RenetServer,
     },
     transport::NetcodeServerPlugin,
     RenetServerPlugin,
 };
+pub use components::{Armor, Dead, Health, Projectile, SpawnProtection};
+pub use config::{AuthenticationMode, ServerConfig, ServerStartError, SpawnStrategy};
+pub use events::{DamageEvent, HitLocation};
 use resources::*;
 use std::{
     net::UdpSocket,
+    sync::Mutex,
     time::{Duration, SystemTime},
 };
 use systems::*;
 
-use crate::{connection_config, PROTOCOL_ID};
+use crate::{
+    channel::ChannelRegistry,
+    map::{MapPlugin, WorldAssets},
+    simulation::{SimulationPlugin, SimulationSet},
+    NetworkEntityMap, ProtocolPlugin,
+};
 
 #[derive(Clone, Eq, PartialEq, Debug, Hash, Default, States)]
 enum ServerStates {
@@ -28,47 +40,129 @@ enum ServerStates {
     Playing,
 }
 
-// TODO: Parameterize this with: max_clients, etc.
-pub struct ServerPlugin;
+pub struct ServerPlugin {
+    config: ServerConfig,
+    /// Taken when the plugin is built.
+    transport: Mutex<Option<NetcodeServerTransport>>,
+}
 
-impl Plugin for ServerPlugin {
-    fn build(&self, app: &mut App) {
-        let public_addr = "127.0.0.1:5000".parse().unwrap();
-        let socket = UdpSocket::bind(public_addr).unwrap();
+impl ServerPlugin {
+    /// Validates the config and binds the server socket, so that bad configs and taken ports are
+    /// reported before the app starts.
+    pub fn new(config: ServerConfig) -> Result<Self, ServerStartError> {
+        config.validate()?;
+
+        let socket = UdpSocket::bind(config.bind_addr)
+            .map_err(|err| ServerStartError::Bind(config.bind_addr, err))?;
         let current_time = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap();
-        let server_config = ServerConfig {
+        let server_config = NetcodeServerConfig {
             current_time,
-            max_clients: 64,
-            protocol_id: PROTOCOL_ID,
-            public_addresses: vec![public_addr],
-            authentication: ServerAuthentication::Unsecure,
+            max_clients: config.max_clients,
+            protocol_id: config.protocol_id,
+            public_addresses: vec![config.public_addr()],
+            authentication: config.server_authentication()?,
         };
+        let transport = NetcodeServerTransport::new(server_config, socket)
+            .map_err(|err| ServerStartError::Bind(config.bind_addr, err))?;
+
+        Ok(Self {
+            config,
+            transport: Mutex::new(Some(transport)),
+        })
+    }
+}
 
-        let transport = NetcodeServerTransport::new(server_config, socket).unwrap();
-        let server = RenetServer::new(connection_config());
+impl Plugin for ServerPlugin {
+    fn build(&self, app: &mut App) {
+        let config = &self.config;
+        let transport = self
+            .transport
+            .lock()
+            .unwrap()
+            .take()
+            .expect("ServerPlugin can only be added once");
 
         app.add_plugins(DefaultPlugins.build().disable::<WinitPlugin>())
             .add_plugins(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
-                1.0 / 60.0,
+                1.0 / config.tick_rate,
             )))
+            .add_plugins(SimulationPlugin {
+                tick_rate: config.tick_rate,
+            })
             .add_plugins(RenetServerPlugin)
             .add_plugins(NetcodeServerPlugin)
+            .add_plugins(ProtocolPlugin)
+            .add_plugins(MapPlugin {
+                path: config.map.clone(),
+                collider_cache: config.collider_cache.clone(),
+            })
             .add_state::<ServerStates>()
             .add_loading_state(
                 LoadingState::new(ServerStates::AssetLoading)
                     .continue_to_state(ServerStates::Playing)
                     .load_collection::<WorldAssets>(),
-            )
-            .init_resource::<ServerLobby>()
+            );
+
+        let server = RenetServer::new(app.world.resource::<ChannelRegistry>().connection_config());
+
+        app.init_resource::<ServerLobby>()
+            .init_resource::<NetworkEntityAllocator>()
+            .init_resource::<ProjectileIdAllocator>()
+            .init_resource::<NetworkEntityMap>()
+            .init_resource::<SnapshotHistory>()
+            .init_resource::<BandwidthReport>()
+            .init_resource::<MessageViolations>()
+            .init_resource::<PendingDisconnects>()
+            .init_resource::<SpawnPoints>()
+            .insert_resource(SnapshotTimer(Timer::from_seconds(
+                1.0 / config.snapshot_rate as f32,
+                TimerMode::Repeating,
+            )))
+            .insert_resource(config.clone())
             .insert_resource(server)
             .insert_resource(transport)
-            .add_systems(OnEnter(ServerStates::Playing), setup)
+            .add_event::<DamageEvent>()
+            .add_systems(Update, insert_spawn_points)
             .add_systems(
                 Update,
-                (handle_server_events, handle_client_messages)
+                (
+                    handle_server_events,
+                    handle_client_messages,
+                    handle_player_inputs,
+                    disconnect_rejected,
+                    report_bandwidth,
+                )
                     .run_if(in_state(ServerStates::Playing)),
+            )
+            .add_systems(
+                Update,
+                (
+                    kill_volume_damage,
+                    apply_damage,
+                    respawn_players,
+                    expire_spawn_protection,
+                )
+                    .chain()
+                    .after(handle_player_inputs)
+                    .run_if(in_state(ServerStates::Playing)),
+            )
+            .add_systems(
+                FixedUpdate,
+                (advance_melee_swings, advance_projectiles)
+                    .after(PhysicsSet::Writeback)
+                    .run_if(in_state(ServerStates::Playing)),
+            )
+            .add_systems(
+                FixedUpdate,
+                apply_player_inputs
+                    .in_set(SimulationSet::Input)
+                    .run_if(in_state(ServerStates::Playing)),
+            )
+            .add_systems(
+                PostUpdate,
+                (send_snapshots, replicate_despawns).run_if(in_state(ServerStates::Playing)),
             );
     }
 }
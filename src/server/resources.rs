@@ -1,15 +1,277 @@
-use bevy::{gltf::*, prelude::*};
-use bevy_asset_loader::prelude::*;
+use super::SpawnStrategy;
+use crate::{map::SpawnPoint, projectile::ProjectileId, snapshot::WorldSnapshot, NetworkEntity};
+use bevy::prelude::*;
 use bevy_renet::renet::ClientId;
-use std::collections::HashMap;
-
-#[derive(AssetCollection, Resource)]
-pub struct WorldAssets {
-    #[asset(path = "playground.glb")]
-    pub playground: Handle<Gltf>,
-}
+use std::collections::{HashMap, VecDeque};
 
 #[derive(Debug, Default, Resource)]
 pub struct ServerLobby {
+    /// Names of the clients that completed the handshake.
+    pub accepted: HashMap<ClientId, String>,
     pub players: HashMap<ClientId, Entity>,
 }
+
+/// Rejected clients, disconnected once their timer runs out so the rejection reaches them.
+#[derive(Debug, Default, Resource)]
+pub struct PendingDisconnects {
+    pub clients: HashMap<ClientId, Timer>,
+}
+
+impl PendingDisconnects {
+    pub const DELAY_SECONDS: f32 = 1.0;
+
+    pub fn push(&mut self, client_id: ClientId) {
+        self.clients.insert(
+            client_id,
+            Timer::from_seconds(Self::DELAY_SECONDS, TimerMode::Once),
+        );
+    }
+}
+
+/// Spawn points of the map, from [`MapLoaded`](crate::map::MapLoaded).
+#[derive(Debug, Default, Resource)]
+pub struct SpawnPoints {
+    pub points: Vec<SpawnPoint>,
+    next: usize,
+}
+
+impl SpawnPoints {
+    pub fn new(points: Vec<SpawnPoint>) -> Self {
+        Self { points, next: 0 }
+    }
+
+    /// Picks a spawn point open to `team` that passes `fits`, or `None` when there is none.
+    /// Without a `team` every spawn point is open. `others` are the positions of the players to
+    /// keep away from.
+    pub fn choose(
+        &mut self,
+        strategy: SpawnStrategy,
+        team: Option<&str>,
+        others: &[Vec3],
+        fits: impl Fn(Vec3) -> bool,
+    ) -> Option<Vec3> {
+        let candidates: Vec<usize> = (0..self.points.len())
+            .filter(|i| {
+                let point = &self.points[*i];
+                team.is_none() || point.team.is_none() || point.team.as_deref() == team
+            })
+            .filter(|i| fits(self.points[*i].position))
+            .collect();
+
+        let index = match strategy {
+            SpawnStrategy::Random => fastrand::choice(candidates)?,
+            SpawnStrategy::RoundRobin => {
+                let len = self.points.len();
+                let index = (0..len)
+                    .map(|offset| (self.next + offset) % len)
+                    .find(|i| candidates.contains(i))?;
+                self.next = (index + 1) % len;
+                index
+            }
+            SpawnStrategy::FarthestFromEnemies => candidates.into_iter().max_by(|a, b| {
+                let distance = |i: usize| {
+                    others
+                        .iter()
+                        .map(|other| other.distance(self.points[i].position))
+                        .fold(f32::INFINITY, f32::min)
+                };
+                distance(*a).total_cmp(&distance(*b))
+            })?,
+        };
+
+        Some(self.points[index].position)
+    }
+}
+
+/// Hands out network ids for newly replicated entities.
+#[derive(Debug, Default, Resource)]
+pub struct NetworkEntityAllocator {
+    next: u32,
+}
+
+impl NetworkEntityAllocator {
+    pub fn allocate(&mut self) -> NetworkEntity {
+        let network_entity = NetworkEntity(self.next);
+        self.next = self.next.wrapping_add(1);
+        network_entity
+    }
+}
+
+/// Hands out ids for projectile messages.
+#[derive(Debug, Default, Resource)]
+pub struct ProjectileIdAllocator {
+    next: u32,
+}
+
+impl ProjectileIdAllocator {
+    pub fn allocate(&mut self) -> ProjectileId {
+        let id = ProjectileId(self.next);
+        self.next = self.next.wrapping_add(1);
+        id
+    }
+}
+
+#[derive(Debug, Resource)]
+pub struct SnapshotTimer(pub Timer);
+
+/// Snapshots sent to one client, kept until they are too old to serve as a delta baseline.
+#[derive(Debug, Default)]
+pub struct ClientSnapshots {
+    pub sent: VecDeque<(u64, WorldSnapshot)>,
+    pub acked: Option<u64>,
+}
+
+impl ClientSnapshots {
+    pub const CAPACITY: usize = 32;
+
+    pub fn push(&mut self, tick: u64, snapshot: WorldSnapshot) {
+        self.sent.push_back((tick, snapshot));
+        while self.sent.len() > Self::CAPACITY {
+            self.sent.pop_front();
+        }
+    }
+
+    pub fn acknowledge(&mut self, tick: u64) {
+        if self.acked.is_none_or(|acked| tick > acked) {
+            self.acked = Some(tick);
+        }
+    }
+
+    /// Newest acknowledged snapshot that is still in the history.
+    pub fn baseline(&self) -> Option<(u64, &WorldSnapshot)> {
+        let acked = self.acked?;
+        self.sent
+            .iter()
+            .find(|(tick, _)| *tick == acked)
+            .map(|(tick, snapshot)| (*tick, snapshot))
+    }
+}
+
+#[derive(Debug, Default, Resource)]
+pub struct SnapshotHistory {
+    pub clients: HashMap<ClientId, ClientSnapshots>,
+}
+
+/// Malformed or unexpected messages received from each client.
+#[derive(Debug, Default, Resource)]
+pub struct MessageViolations {
+    pub clients: HashMap<ClientId, u32>,
+}
+
+impl MessageViolations {
+    /// Violations after which a client is disconnected.
+    pub const LIMIT: u32 = 10;
+
+    /// Records a violation, returning whether the client is now over the limit.
+    pub fn record(&mut self, client_id: ClientId, reason: impl std::fmt::Display) -> bool {
+        println!("Client {} sent an invalid message: {}", client_id, reason);
+
+        let count = self.clients.entry(client_id).or_default();
+        *count += 1;
+        *count > Self::LIMIT
+    }
+}
+
+/// Snapshot bytes sent to each client since the last report.
+#[derive(Debug, Resource)]
+pub struct BandwidthReport {
+    pub timer: Timer,
+    pub snapshot_bytes: HashMap<ClientId, usize>,
+}
+
+impl Default for BandwidthReport {
+    fn default() -> Self {
+        Self {
+            timer: Timer::from_seconds(5.0, TimerMode::Repeating),
+            snapshot_bytes: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn_points(count: usize) -> SpawnPoints {
+        SpawnPoints::new(
+            (0..count)
+                .map(|i| SpawnPoint {
+                    name: format!("spawn_{}", i),
+                    position: Vec3::new(i as f32, 0.0, 0.0),
+                    team: None,
+                })
+                .collect(),
+        )
+    }
+
+    fn round_robin(points: &mut SpawnPoints, fits: impl Fn(Vec3) -> bool) -> Option<Vec3> {
+        points.choose(SpawnStrategy::RoundRobin, None, &[], fits)
+    }
+
+    #[test]
+    fn round_robin_wraps_around() {
+        let mut points = spawn_points(3);
+
+        let chosen: Vec<_> = (0..4)
+            .map(|_| round_robin(&mut points, |_| true).unwrap().x)
+            .collect();
+
+        assert_eq!(chosen, [0.0, 1.0, 2.0, 0.0]);
+    }
+
+    #[test]
+    fn round_robin_skips_blocked_points_across_wraparound() {
+        let mut points = spawn_points(4);
+        assert_eq!(round_robin(&mut points, |_| true).unwrap().x, 0.0);
+        assert_eq!(round_robin(&mut points, |_| true).unwrap().x, 1.0);
+        assert_eq!(round_robin(&mut points, |_| true).unwrap().x, 2.0);
+
+        // Point 3 is next but blocked, so the search wraps to point 0.
+        let fits = |position: Vec3| position.x != 3.0 && position.x != 1.0;
+        assert_eq!(round_robin(&mut points, fits).unwrap().x, 0.0);
+        assert_eq!(round_robin(&mut points, fits).unwrap().x, 2.0);
+        assert_eq!(round_robin(&mut points, |_| true).unwrap().x, 3.0);
+    }
+
+    #[test]
+    fn round_robin_returns_none_when_every_point_is_blocked() {
+        let mut points = spawn_points(3);
+        assert_eq!(round_robin(&mut points, |_| false), None);
+        assert_eq!(round_robin(&mut points, |_| true).unwrap().x, 0.0);
+    }
+
+    #[test]
+    fn any_team_spawns_at_tagged_points() {
+        let mut points = spawn_points(2);
+        points.points[0].team = Some(String::from("red"));
+        points.points[1].team = Some(String::from("blue"));
+
+        assert_eq!(round_robin(&mut points, |_| true).unwrap().x, 0.0);
+        assert_eq!(round_robin(&mut points, |_| true).unwrap().x, 1.0);
+
+        let farthest = points.choose(
+            SpawnStrategy::FarthestFromEnemies,
+            None,
+            &[Vec3::ZERO],
+            |_| true,
+        );
+        assert_eq!(farthest, Some(Vec3::X));
+    }
+
+    #[test]
+    fn round_robin_skips_other_teams() {
+        let mut points = spawn_points(3);
+        points.points[1].team = Some(String::from("red"));
+
+        let chosen: Vec<_> = (0..3)
+            .map(|_| {
+                points
+                    .choose(SpawnStrategy::RoundRobin, Some("blue"), &[], |_| true)
+                    .unwrap()
+                    .x
+            })
+            .collect();
+
+        assert_eq!(chosen, [0.0, 2.0, 0.0]);
+    }
+}
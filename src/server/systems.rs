@@ -1,97 +1,1019 @@
-use super::resources::*;
-use crate::{ClientChannel, ClientMessage, ServerChannel, ServerMessage};
-use bevy::{gltf::*, prelude::*};
+use super::{components::*, events::*, resources::*, ServerConfig};
+use crate::{
+    channel::{FromClient, InvalidMessage, NetworkServer},
+    combat::{Defense, MeleeWeapon, SwingDirection, STAGGER_SECONDS, SWING_ORIGIN_HEIGHT},
+    controller::{FpsCharacterController, FpsControllerInput},
+    map::{MapLoaded, MapSettings, Volume},
+    player_name_from_user_data,
+    projectile::{Flight, RangedWeapon, GRAVITY, LAUNCH_HEIGHT, PROJECTILE_LIFETIME},
+    simulation::SimulationTick,
+    snapshot::{QuantizedPlayer, WorldSnapshot},
+    ClientMessage, DamageType, NetworkEntity, NetworkEntityMap, PlayerInfo, PlayerInput,
+    PlayerSnapshot, ProjectileInfo, RejectReason, ServerInfo, ServerMessage, Snapshot, SnapshotAck,
+    PROTOCOL_VERSION,
+};
+use bevy::{ecs::system::SystemParam, prelude::*};
 use bevy_rapier3d::prelude::*;
-use bevy_renet::renet::{RenetServer, ServerEvent};
+use bevy_renet::renet::{transport::NetcodeServerTransport, ClientId, RenetServer, ServerEvent};
 use std::collections::hash_map::Entry::Vacant;
 
-pub fn setup(
-    mut commands: Commands,
-    world: ResMut<WorldAssets>,
-    gltf_assets: Res<Assets<Gltf>>,
-    gltf_mesh_assets: Res<Assets<GltfMesh>>,
-    gltf_node_assets: Res<Assets<GltfNode>>,
-    mesh_assets: Res<Assets<Mesh>>,
-) {
-    if let Some(gltf) = gltf_assets.get(&world.playground) {
-        for node in &gltf.nodes {
-            let node = gltf_node_assets.get(node).unwrap();
-            if let Some(gltf_mesh) = node.mesh.clone() {
-                let gltf_mesh = gltf_mesh_assets.get(&gltf_mesh).unwrap();
-                for mesh_primitive in &gltf_mesh.primitives {
-                    let mesh = mesh_assets.get(&mesh_primitive.mesh).unwrap();
-                    commands.spawn((
-                        Collider::from_bevy_mesh(mesh, &ComputedColliderShape::TriMesh).unwrap(),
-                        RigidBody::Fixed,
-                        TransformBundle::from_transform(node.transform),
-                    ));
-                }
-            }
-        }
+pub fn insert_spawn_points(mut commands: Commands, mut loaded: EventReader<MapLoaded>) {
+    for map in loaded.read() {
+        println!("Found {} spawn points", map.spawn_points.len());
+        commands.insert_resource(SpawnPoints::new(map.spawn_points.clone()));
     }
 }
 
-pub fn handle_server_events(mut events: EventReader<ServerEvent>, mut server: ResMut<RenetServer>) {
+#[allow(clippy::too_many_arguments)]
+pub fn handle_server_events(
+    mut commands: Commands,
+    mut events: EventReader<ServerEvent>,
+    mut server: NetworkServer,
+    mut lobby: ResMut<ServerLobby>,
+    mut history: ResMut<SnapshotHistory>,
+    mut report: ResMut<BandwidthReport>,
+    mut violations: ResMut<MessageViolations>,
+    mut pending: ResMut<PendingDisconnects>,
+) {
     for event in events.read() {
         match event {
             ServerEvent::ClientConnected { client_id } => {
                 println!("Client connected: {}", client_id);
-
-                let message =
-                    bincode::serialize(&ServerMessage::PlayerConnected { id: *client_id }).unwrap();
-                server.broadcast_message(ServerChannel::ServerMessage, message);
             }
             ServerEvent::ClientDisconnected { client_id, reason } => {
                 println!("Client disconnected: {} ({})", client_id, reason);
 
-                let message =
-                    bincode::serialize(&ServerMessage::PlayerDisconnected { id: *client_id })
-                        .unwrap();
-                server.broadcast_message(ServerChannel::ServerMessage, message);
+                history.clients.remove(client_id);
+                report.snapshot_bytes.remove(client_id);
+                violations.clients.remove(client_id);
+                pending.clients.remove(client_id);
+
+                // The despawn itself is replicated by `replicate_despawns`.
+                if let Some(entity) = lobby.players.remove(client_id) {
+                    commands.entity(entity).despawn_recursive();
+                }
+
+                if lobby.accepted.remove(client_id).is_some() {
+                    server.broadcast(&ServerMessage::PlayerDisconnected { id: *client_id });
+                }
             }
         }
     }
 }
 
+/// Players and projectiles a newly spawning client has to be told about.
+#[derive(SystemParam)]
+pub struct ExistingEntities<'w, 's> {
+    players: Query<
+        'w,
+        's,
+        (
+            &'static NetworkEntity,
+            &'static Name,
+            &'static Transform,
+            Has<Dead>,
+        ),
+    >,
+    projectiles: Query<'w, 's, &'static Projectile>,
+}
+
+impl ExistingEntities<'_, '_> {
+    fn world_state(&self) -> ServerMessage {
+        ServerMessage::WorldState {
+            players: self
+                .players
+                .iter()
+                .map(|(entity, name, transform, dead)| PlayerInfo {
+                    entity: *entity,
+                    name: name.to_string(),
+                    position: transform.translation,
+                    dead,
+                })
+                .collect(),
+            projectiles: self
+                .projectiles
+                .iter()
+                .map(|projectile| ProjectileInfo {
+                    id: projectile.id,
+                    kind: projectile.kind,
+                    position: projectile.flight.position,
+                    velocity: projectile.flight.velocity,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn handle_client_messages(
     mut commands: Commands,
+    mut messages: EventReader<FromClient<ClientMessage>>,
     mut lobby: ResMut<ServerLobby>,
-    mut server: ResMut<RenetServer>,
+    mut allocator: ResMut<NetworkEntityAllocator>,
+    mut entity_map: ResMut<NetworkEntityMap>,
+    mut server: NetworkServer,
+    mut violations: ResMut<MessageViolations>,
+    mut pending: ResMut<PendingDisconnects>,
+    mut spawn_points: ResMut<SpawnPoints>,
+    context: Res<RapierContext>,
+    config: Res<ServerConfig>,
+    transport: Res<NetcodeServerTransport>,
+    existing: ExistingEntities,
+    mut weapons: Query<(Entity, &NetworkEntity, &mut MeleeWeapon), Without<Dead>>,
+    mut launchers: Query<(&Transform, &FpsControllerInput, &mut RangedWeapon), Without<Dead>>,
+    mut projectile_ids: ResMut<ProjectileIdAllocator>,
 ) {
-    for client_id in server.clients_id() {
-        while let Some(message) = server.receive_message(client_id, ClientChannel::ClientMessage) {
-            match bincode::deserialize(&message).unwrap() {
-                ClientMessage::SpawnMe => {
-                    if let Vacant(entry) = lobby.players.entry(client_id) {
-                        println!("Spawning player for client {}", client_id);
+    for FromClient { client_id, message } in messages.read() {
+        let client_id = *client_id;
 
-                        let position = Vec3::new(0.0, 1.0, 0.0);
+        match message {
+            ClientMessage::Hello { version, name }
+                if !lobby.accepted.contains_key(&client_id)
+                    && !pending.clients.contains_key(&client_id) =>
+            {
+                match admit(&config, &lobby, client_id, *version) {
+                    Ok(()) => {
+                        // Names from connect tokens are signed by whoever issued the token, so
+                        // they take precedence over the one the client claims.
+                        let name = transport
+                            .user_data(client_id)
+                            .and_then(|user_data| player_name_from_user_data(&user_data))
+                            .filter(|name| !name.is_empty())
+                            .unwrap_or_else(|| name.clone());
+                        println!("Client {} joined as {}", client_id, name);
+                        lobby.accepted.insert(client_id, name);
 
-                        let entity = commands
-                            .spawn((Transform::from_translation(position),))
-                            .id();
-                        entry.insert(entity);
-
-                        let message = bincode::serialize(&ServerMessage::SpawnPlayer {
-                            server_entity: entity,
-                            position,
-                        })
-                        .unwrap();
-                        server.broadcast_message_except(
+                        server.send(
                             client_id,
-                            ServerChannel::ServerMessage,
-                            message,
+                            &ServerMessage::Welcome {
+                                info: ServerInfo {
+                                    map: config.map.clone(),
+                                    tick_rate: config.tick_rate,
+                                    snapshot_rate: config.snapshot_rate,
+                                    max_players: config.max_players,
+                                },
+                            },
                         );
+                        server.broadcast(&ServerMessage::PlayerConnected { id: client_id });
+                    }
+                    Err(reason) => {
+                        println!("Rejecting client {}: {}", client_id, reason);
 
-                        let message = bincode::serialize(&ServerMessage::SpawnHim {
-                            server_entity: entity,
+                        server.send(client_id, &ServerMessage::Rejected { reason });
+                        pending.push(client_id);
+                    }
+                }
+            }
+            ClientMessage::SpawnMe { weapon } if lobby.accepted.contains_key(&client_id) => {
+                let name = lobby.accepted[&client_id].clone();
+                if let Vacant(entry) = lobby.players.entry(client_id) {
+                    println!("Spawning player for client {}", client_id);
+
+                    let others: Vec<Vec3> = existing
+                        .players
+                        .iter()
+                        .map(|(_, _, transform, _)| transform.translation)
+                        .collect();
+                    let position = spawn_position(&config, &mut spawn_points, &context, &others);
+
+                    server.send(client_id, &existing.world_state());
+
+                    let network_entity = allocator.allocate();
+                    let entity = commands
+                        .spawn((
+                            FpsCharacterController::default()
+                                .with_translation(position)
+                                .without_input(),
+                            PlayerInputs::default(),
+                            Health::default(),
+                            Armor::new(config.starting_armor),
+                            MeleeWeapon {
+                                kind: config.melee_weapon(*weapon),
+                                ..default()
+                            },
+                            RangedWeapon::default(),
+                            network_entity,
+                            Name::new(name.clone()),
+                        ))
+                        .id();
+                    entry.insert(entity);
+                    entity_map.insert(network_entity, entity);
+
+                    server.broadcast_except(
+                        client_id,
+                        &ServerMessage::SpawnPlayer {
+                            entity: network_entity,
+                            name,
+                            position,
+                        },
+                    );
+                    server.send(
+                        client_id,
+                        &ServerMessage::SpawnHim {
+                            entity: network_entity,
                             position,
-                        })
-                        .unwrap();
-                        server.send_message(client_id, ServerChannel::ServerMessage, message);
+                        },
+                    );
+                }
+            }
+            ClientMessage::Attack {
+                direction,
+                sequence,
+            } if lobby.accepted.contains_key(&client_id) => {
+                let Some((entity, network_entity, mut weapon)) = lobby
+                    .players
+                    .get(&client_id)
+                    .and_then(|entity| weapons.get_mut(*entity).ok())
+                else {
+                    continue;
+                };
+                if !weapon.attack(*direction, *sequence) {
+                    continue;
+                }
+                if let Ok((_, _, mut launcher)) = launchers.get_mut(entity) {
+                    launcher.drawn = None;
+                }
+
+                // Attacking gives up spawn protection.
+                commands.entity(entity).remove::<SpawnProtection>();
+                server.broadcast(&ServerMessage::Swing {
+                    attacker: *network_entity,
+                    weapon: weapon.kind,
+                    direction: *direction,
+                });
+            }
+            ClientMessage::Guard { direction } if lobby.accepted.contains_key(&client_id) => {
+                let Some((entity, network_entity, mut weapon)) = lobby
+                    .players
+                    .get(&client_id)
+                    .and_then(|entity| weapons.get_mut(*entity).ok())
+                else {
+                    continue;
+                };
+                let swinging = weapon.swing.is_some();
+                if !weapon.set_guard(*direction) {
+                    continue;
+                }
+                if let Ok((_, _, mut launcher)) = launchers.get_mut(entity) {
+                    launcher.drawn = None;
+                }
+
+                if swinging && weapon.swing.is_none() {
+                    server.broadcast(&ServerMessage::Feint {
+                        attacker: *network_entity,
+                    });
+                }
+                server.broadcast(&ServerMessage::Guard {
+                    entity: *network_entity,
+                    direction: *direction,
+                });
+            }
+            ClientMessage::Feint if lobby.accepted.contains_key(&client_id) => {
+                let Some((_, network_entity, mut weapon)) = lobby
+                    .players
+                    .get(&client_id)
+                    .and_then(|entity| weapons.get_mut(*entity).ok())
+                else {
+                    continue;
+                };
+                if weapon.feint() {
+                    server.broadcast(&ServerMessage::Feint {
+                        attacker: *network_entity,
+                    });
+                }
+            }
+            ClientMessage::Draw { weapon } if lobby.accepted.contains_key(&client_id) => {
+                let Some((entity, _, melee)) = lobby
+                    .players
+                    .get(&client_id)
+                    .and_then(|entity| weapons.get(*entity).ok())
+                else {
+                    continue;
+                };
+                // Both hands are busy while swinging, guarding or staggered.
+                if melee.swing.is_some() || melee.guard.is_some() || melee.stagger > 0.0 {
+                    continue;
+                }
+                if let Ok((_, _, mut launcher)) = launchers.get_mut(entity) {
+                    launcher.draw(*weapon);
+                }
+            }
+            ClientMessage::Loose if lobby.accepted.contains_key(&client_id) => {
+                let Some(&entity) = lobby.players.get(&client_id) else {
+                    continue;
+                };
+                let Ok((transform, input, mut launcher)) = launchers.get_mut(entity) else {
+                    continue;
+                };
+                let Some(speed) = launcher.loose() else {
+                    continue;
+                };
+
+                let rotation = Quat::from_euler(EulerRot::YXZ, input.yaw, input.pitch, 0.0);
+                let flight = Flight {
+                    position: transform.translation + Vec3::Y * LAUNCH_HEIGHT,
+                    velocity: rotation * Vec3::NEG_Z * speed,
+                };
+                let id = projectile_ids.allocate();
+                commands.spawn(Projectile {
+                    id,
+                    kind: launcher.kind,
+                    owner: entity,
+                    flight,
+                    age: 0.0,
+                });
+                // Attacking gives up spawn protection.
+                commands.entity(entity).remove::<SpawnProtection>();
+                server.broadcast(&ServerMessage::ProjectileSpawned {
+                    id,
+                    kind: launcher.kind,
+                    position: flight.position,
+                    velocity: flight.velocity,
+                });
+            }
+            message => record_violation(
+                &mut violations,
+                &mut server,
+                &mut pending,
+                client_id,
+                format!("unexpected {:?}", message),
+            ),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_player_inputs(
+    mut inputs: EventReader<FromClient<PlayerInput>>,
+    mut acks: EventReader<FromClient<SnapshotAck>>,
+    mut invalid_messages: EventReader<InvalidMessage>,
+    lobby: Res<ServerLobby>,
+    mut server: NetworkServer,
+    mut history: ResMut<SnapshotHistory>,
+    mut violations: ResMut<MessageViolations>,
+    mut pending: ResMut<PendingDisconnects>,
+    mut query: Query<&mut PlayerInputs>,
+) {
+    for FromClient { client_id, message } in inputs.read() {
+        if !valid_input(&message.input) {
+            let reason = format!("invalid input {}", message.sequence);
+            record_violation(
+                &mut violations,
+                &mut server,
+                &mut pending,
+                *client_id,
+                reason,
+            );
+        } else if let Some(mut player_inputs) = lobby
+            .players
+            .get(client_id)
+            .and_then(|entity| query.get_mut(*entity).ok())
+        {
+            player_inputs.push(message.sequence, message.input);
+        }
+    }
+
+    for FromClient { client_id, message } in acks.read() {
+        if let Some(client_snapshots) = history.clients.get_mut(client_id) {
+            client_snapshots.acknowledge(message.tick);
+        }
+    }
+
+    for InvalidMessage { client_id, error } in invalid_messages.read() {
+        record_violation(
+            &mut violations,
+            &mut server,
+            &mut pending,
+            *client_id,
+            error,
+        );
+    }
+}
+
+/// Counts a violation against the client. Once it goes over the limit the client is told why
+/// and disconnected shortly after, like clients rejected during the handshake.
+fn record_violation(
+    violations: &mut MessageViolations,
+    server: &mut NetworkServer,
+    pending: &mut PendingDisconnects,
+    client_id: ClientId,
+    reason: impl std::fmt::Display,
+) {
+    if violations.record(client_id, reason)
+        && server.is_connected(client_id)
+        && !pending.clients.contains_key(&client_id)
+    {
+        println!(
+            "Disconnecting client {}: more than {} invalid messages",
+            client_id,
+            MessageViolations::LIMIT
+        );
+        server.send(
+            client_id,
+            &ServerMessage::Rejected {
+                reason: RejectReason::ProtocolViolation,
+            },
+        );
+        pending.push(client_id);
+    }
+}
+
+/// Used when the map has no spawn points or all of them are blocked.
+const DEFAULT_SPAWN: Vec3 = Vec3::new(0.0, 1.0, 0.0);
+/// Lifts players off the spawn point so the capsule doesn't start touching the floor.
+const SPAWN_CLEARANCE: Vec3 = Vec3::new(0.0, 0.1, 0.0);
+
+/// Picks a free spawn point for a player, keeping away from the players at `others`.
+fn spawn_position(
+    config: &ServerConfig,
+    spawn_points: &mut SpawnPoints,
+    context: &RapierContext,
+    others: &[Vec3],
+) -> Vec3 {
+    spawn_points
+        .choose(config.spawn_strategy, None, others, |position| {
+            capsule_fits(context, position + SPAWN_CLEARANCE)
+        })
+        .map(|position| position + SPAWN_CLEARANCE)
+        .unwrap_or_else(|| {
+            println!("No free spawn point, using the default spawn");
+            DEFAULT_SPAWN
+        })
+}
+
+/// Whether a player capsule standing at `position` overlaps no other collider.
+fn capsule_fits(context: &RapierContext, position: Vec3) -> bool {
+    context
+        .intersection_with_shape(
+            position,
+            Quat::IDENTITY,
+            &FpsCharacterController::collider(),
+            QueryFilter::new().exclude_sensors(),
+        )
+        .is_none()
+}
+
+/// Checks whether a client that said hello may join.
+fn admit(
+    config: &ServerConfig,
+    lobby: &ServerLobby,
+    client_id: ClientId,
+    version: u32,
+) -> Result<(), RejectReason> {
+    if version != PROTOCOL_VERSION {
+        return Err(RejectReason::VersionMismatch {
+            server: PROTOCOL_VERSION,
+            client: version,
+        });
+    }
+    if config.banned_clients.contains(&client_id.raw()) {
+        return Err(RejectReason::Banned);
+    }
+    if lobby.accepted.len() >= config.max_players {
+        return Err(RejectReason::ServerFull);
+    }
+
+    Ok(())
+}
+
+/// Disconnects rejected clients once their rejection had time to arrive.
+pub fn disconnect_rejected(
+    time: Res<Time>,
+    mut pending: ResMut<PendingDisconnects>,
+    mut server: ResMut<RenetServer>,
+) {
+    for (client_id, timer) in pending.clients.iter_mut() {
+        if timer.tick(time.delta()).just_finished() {
+            server.disconnect(*client_id);
+        }
+    }
+}
+
+/// Rejects inputs a well-behaved client can't produce, so they never reach the simulation.
+fn valid_input(input: &FpsControllerInput) -> bool {
+    input.pitch.is_finite()
+        && input.yaw.is_finite()
+        && input.movement.is_finite()
+        && input.movement.abs().max_element() <= 1.0
+}
+
+pub fn apply_player_inputs(
+    mut query: Query<(&mut PlayerInputs, &mut FpsControllerInput, Has<Dead>)>,
+) {
+    for (mut player_inputs, mut input, dead) in query.iter_mut() {
+        if let Some((sequence, next_input)) = player_inputs.queue.pop_front() {
+            // Dead players may still look around, but not move.
+            *input = if dead {
+                FpsControllerInput {
+                    pitch: next_input.pitch,
+                    yaw: next_input.yaw,
+                    ..default()
+                }
+            } else {
+                next_input
+            };
+            player_inputs.last_processed = Some(sequence);
+        }
+    }
+}
+
+/// Kills players standing in kill volumes.
+pub fn kill_volume_damage(
+    context: Res<RapierContext>,
+    volumes: Query<&Volume>,
+    players: Query<(Entity, &Transform), (With<Health>, Without<Dead>)>,
+    mut damage: EventWriter<DamageEvent>,
+) {
+    let is_kill_volume = |collider| volumes.get(collider) == Ok(&Volume::Kill);
+    for (entity, transform) in players.iter() {
+        let in_kill_volume = context
+            .intersection_with_shape(
+                transform.translation,
+                Quat::IDENTITY,
+                &FpsCharacterController::collider(),
+                QueryFilter::new().predicate(&is_kill_volume),
+            )
+            .is_some();
+
+        if in_kill_volume {
+            damage.send(DamageEvent {
+                target: entity,
+                source: None,
+                amount: f32::INFINITY,
+                damage_type: DamageType::Environment,
+                location: HitLocation::Torso,
+            });
+        }
+    }
+}
+
+/// Applies damage to armor and health, and announces the players it kills.
+pub fn apply_damage(
+    mut commands: Commands,
+    mut damage: EventReader<DamageEvent>,
+    mut server: NetworkServer,
+    config: Res<ServerConfig>,
+    mut players: Query<
+        (&mut Health, Option<&mut Armor>),
+        (Without<Dead>, Without<SpawnProtection>),
+    >,
+    network_entities: Query<&NetworkEntity>,
+) {
+    for event in damage.read() {
+        let Ok((mut health, armor)) = players.get_mut(event.target) else {
+            continue;
+        };
+        // An earlier hit this frame may already have killed the player.
+        if health.is_dead() {
+            continue;
+        }
+
+        let mut amount = event.amount * event.location.multiplier();
+        if let Some(mut armor) = armor {
+            amount = armor.absorb(amount);
+        }
+        health.current = (health.current - amount).max(0.0);
+        if !health.is_dead() {
+            continue;
+        }
+
+        // Clients hide dead players, so their bodies must not block movement, swings,
+        // projectiles or spawn points either.
+        commands.entity(event.target).insert((
+            Dead {
+                respawn: Timer::from_seconds(config.respawn_delay, TimerMode::Once),
+            },
+            ColliderDisabled,
+        ));
+        let Ok(victim) = network_entities.get(event.target) else {
+            continue;
+        };
+        let killer = event
+            .source
+            .and_then(|source| network_entities.get(source).ok())
+            .copied();
+        println!(
+            "{:?} killed by {:?} ({})",
+            victim, killer, event.damage_type
+        );
+        server.broadcast(&ServerMessage::PlayerDied {
+            victim: *victim,
+            killer,
+            cause: event.damage_type,
+            respawn_in: config.respawn_delay,
+        });
+    }
+}
+
+/// Ball swept along the arc of melee swings.
+const BLADE_RADIUS: f32 = 0.1;
+
+/// A player reached by a swing during a tick, resolved once every swing has advanced.
+struct MeleeHit {
+    attacker: Entity,
+    target: Entity,
+    direction: SwingDirection,
+    point: Vec3,
+    amount: f32,
+}
+
+/// Advances melee swings, guards and staggers by one tick, sweeping the blade along the part of
+/// its arc covered during the tick. The first player in its way is damaged, unless they face the
+/// attacker and block, parry or chamber the swing. Hitting the world ends the release early.
+#[allow(clippy::too_many_arguments)]
+pub fn advance_melee_swings(
+    time: Res<Time>,
+    context: Res<RapierContext>,
+    lobby: Res<ServerLobby>,
+    mut server: NetworkServer,
+    mut attackers: Query<(
+        Entity,
+        &NetworkEntity,
+        &Transform,
+        &FpsControllerInput,
+        &mut MeleeWeapon,
+        Has<Dead>,
+    )>,
+    targets: Query<(), With<Health>>,
+    mut damage: EventWriter<DamageEvent>,
+) {
+    let blade = Collider::ball(BLADE_RADIUS);
+    let mut hits = Vec::new();
+
+    for (entity, _, transform, input, mut weapon, dead) in attackers.iter_mut() {
+        weapon.tick(time.delta_seconds());
+        if dead {
+            weapon.swing = None;
+            weapon.guard = None;
+            continue;
+        }
+
+        let stats = weapon.kind.stats();
+        let Some(swing) = weapon.swing.as_mut() else {
+            continue;
+        };
+
+        let from = stats.release_progress(swing.elapsed);
+        swing.elapsed += time.delta_seconds();
+        let to = stats.release_progress(swing.elapsed);
+
+        if to > from {
+            let origin = transform.translation + Vec3::Y * SWING_ORIGIN_HEIGHT;
+            let rotation = Quat::from_euler(EulerRot::YXZ, input.yaw, input.pitch, 0.0);
+            let start = origin + rotation * stats.tip_offset(swing.direction, from);
+            let end = origin + rotation * stats.tip_offset(swing.direction, to);
+
+            let hit = &swing.hit;
+            let not_hit_yet = |collider| !hit.contains(&collider);
+            let filter = QueryFilter::new()
+                .exclude_sensors()
+                .exclude_collider(entity)
+                .predicate(&not_hit_yet);
+
+            if let Some((target, toi)) = context.cast_shape(
+                start,
+                Quat::IDENTITY,
+                end - start,
+                &blade,
+                1.0,
+                true,
+                filter,
+            ) {
+                if targets.contains(target) {
+                    hits.push(MeleeHit {
+                        attacker: entity,
+                        target,
+                        direction: swing.direction,
+                        point: start + (end - start) * toi.toi,
+                        amount: stats.damage_at(from + (to - from) * toi.toi),
+                    });
+                    swing.hit.push(target);
+                } else {
+                    swing.elapsed = stats.windup + stats.release;
+                }
+            }
+        }
+
+        if stats.phase_at(swing.elapsed).is_none() {
+            weapon.swing = None;
+        }
+    }
+
+    for hit in hits {
+        let Ok([attacker, defender]) = attackers.get_many_mut([hit.attacker, hit.target]) else {
+            continue;
+        };
+        let (_, attacker_network, attacker_transform, _, mut attacker_weapon, _) = attacker;
+        let (_, defender_network, defender_transform, defender_input, defender_weapon, _) =
+            defender;
+
+        let forward = Quat::from_rotation_y(defender_input.yaw) * Vec3::NEG_Z;
+        let facing =
+            forward.dot(attacker_transform.translation - defender_transform.translation) > 0.0;
+        let Some(defense) = facing
+            .then(|| defender_weapon.defend(hit.direction))
+            .flatten()
+        else {
+            damage.send(DamageEvent {
+                target: hit.target,
+                source: Some(hit.attacker),
+                amount: hit.amount,
+                damage_type: DamageType::Melee,
+                location: HitLocation::from_height(hit.point.y - defender_transform.translation.y),
+            });
+            continue;
+        };
+
+        let stats = attacker_weapon.kind.stats();
+        if defense == Defense::Parried {
+            attacker_weapon.swing = None;
+            attacker_weapon.stagger = STAGGER_SECONDS;
+        } else if let Some(swing) = attacker_weapon.swing.as_mut() {
+            swing.elapsed = swing.elapsed.max(stats.windup + stats.release);
+        }
+        let message = ServerMessage::Clash {
+            attacker: *attacker_network,
+            defender: *defender_network,
+            defense,
+        };
+        for (client_id, _) in lobby
+            .players
+            .iter()
+            .filter(|(_, entity)| [hit.attacker, hit.target].contains(entity))
+        {
+            server.send(*client_id, &message);
+        }
+    }
+}
+
+/// Advances projectiles and ranged weapon timers by one tick, sweeping each projectile along its
+/// flight path. Players in the way are damaged, the map stops projectiles and may hold them stuck,
+/// and projectiles flying too long are removed.
+#[allow(clippy::too_many_arguments)]
+pub fn advance_projectiles(
+    mut commands: Commands,
+    time: Res<Time>,
+    settings: Res<MapSettings>,
+    context: Res<RapierContext>,
+    mut server: NetworkServer,
+    mut launchers: Query<(&mut RangedWeapon, Has<Dead>)>,
+    mut projectiles: Query<(Entity, &mut Projectile)>,
+    targets: Query<&Transform, With<Health>>,
+    mut damage: EventWriter<DamageEvent>,
+) {
+    let dt = time.delta_seconds();
+    let gravity = settings.gravity.unwrap_or(GRAVITY);
+
+    for (mut launcher, dead) in launchers.iter_mut() {
+        launcher.tick(dt);
+        if dead {
+            launcher.drawn = None;
+        }
+    }
+
+    for (entity, mut projectile) in projectiles.iter_mut() {
+        let stats = projectile.kind.stats();
+        let start = projectile.flight.position;
+        projectile.flight.step(&stats, gravity, dt);
+        projectile.age += dt;
+        let end = projectile.flight.position;
+
+        let filter = QueryFilter::new()
+            .exclude_sensors()
+            .exclude_collider(projectile.owner);
+        let hit = context.cast_shape(
+            start,
+            Quat::IDENTITY,
+            end - start,
+            &Collider::ball(stats.radius),
+            1.0,
+            true,
+            filter,
+        );
+
+        let (position, stuck) = match hit {
+            Some((target, toi)) => {
+                let point = start + (end - start) * toi.toi;
+                match targets.get(target) {
+                    Ok(target_transform) => {
+                        damage.send(DamageEvent {
+                            target,
+                            source: Some(projectile.owner),
+                            amount: stats.damage_at(projectile.flight.velocity.length()),
+                            damage_type: DamageType::Projectile,
+                            location: HitLocation::from_height(
+                                point.y - target_transform.translation.y,
+                            ),
+                        });
+                        (point, false)
                     }
+                    Err(_) => (point, stats.sticks),
                 }
             }
+            None if projectile.age >= PROJECTILE_LIFETIME => (end, false),
+            None => continue,
+        };
+
+        server.broadcast(&ServerMessage::ProjectileImpact {
+            id: projectile.id,
+            position,
+            stuck,
+        });
+        commands.entity(entity).despawn();
+    }
+}
+
+/// Brings dead players back at a spawn point once their respawn delay is over.
+#[allow(clippy::too_many_arguments)]
+pub fn respawn_players(
+    mut commands: Commands,
+    time: Res<Time>,
+    config: Res<ServerConfig>,
+    context: Res<RapierContext>,
+    mut spawn_points: ResMut<SpawnPoints>,
+    mut server: NetworkServer,
+    mut dead: Query<(
+        Entity,
+        &NetworkEntity,
+        &mut Dead,
+        &mut Health,
+        &mut Armor,
+        &mut Transform,
+        &mut Velocity,
+    )>,
+    alive: Query<&Transform, (With<Health>, Without<Dead>)>,
+) {
+    let mut others: Vec<Vec3> = alive
+        .iter()
+        .map(|transform| transform.translation)
+        .collect();
+
+    for (entity, network_entity, mut dead, mut health, mut armor, mut transform, mut velocity) in
+        dead.iter_mut()
+    {
+        if !dead.respawn.tick(time.delta()).finished() {
+            continue;
+        }
+
+        let position = spawn_position(&config, &mut spawn_points, &context, &others);
+        others.push(position);
+        println!("Respawning {:?} at {:?}", network_entity, position);
+
+        health.current = health.max;
+        *armor = Armor::new(config.starting_armor);
+        transform.translation = position;
+        *velocity = Velocity::zero();
+        commands
+            .entity(entity)
+            .remove::<(Dead, ColliderDisabled)>()
+            .insert(SpawnProtection(Timer::from_seconds(
+                config.spawn_protection,
+                TimerMode::Once,
+            )));
+
+        server.broadcast(&ServerMessage::PlayerRespawned {
+            entity: *network_entity,
+            position,
+        });
+    }
+}
+
+pub fn expire_spawn_protection(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut query: Query<(Entity, &mut SpawnProtection)>,
+) {
+    for (entity, mut protection) in query.iter_mut() {
+        if protection.0.tick(time.delta()).finished() {
+            commands.entity(entity).remove::<SpawnProtection>();
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn send_snapshots(
+    time: Res<Time>,
+    fixed_time: Res<Time<Fixed>>,
+    tick: Res<SimulationTick>,
+    lobby: Res<ServerLobby>,
+    mut timer: ResMut<SnapshotTimer>,
+    mut history: ResMut<SnapshotHistory>,
+    mut report: ResMut<BandwidthReport>,
+    mut server: NetworkServer,
+    query: Query<(
+        &NetworkEntity,
+        &Transform,
+        &Velocity,
+        &FpsControllerInput,
+        &PlayerInputs,
+        Option<&KinematicCharacterControllerOutput>,
+    )>,
+) {
+    if !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let dt = fixed_time.timestep().as_secs_f32();
+    let snapshot = WorldSnapshot {
+        players: lobby
+            .players
+            .values()
+            .filter_map(|entity| {
+                let (network_entity, transform, velocity, input, _, output) =
+                    query.get(*entity).ok()?;
+                // Horizontal velocity only lives in the controller output, while the vertical
+                // velocity has to be the controller's own so the owning client replays from it.
+                let (velocity, grounded) = match output {
+                    Some(output) if dt > 0.0 => {
+                        let moved = output.effective_translation / dt;
+                        (
+                            Vec3::new(moved.x, velocity.linvel.y, moved.z),
+                            output.grounded,
+                        )
+                    }
+                    _ => (velocity.linvel, false),
+                };
+                let player = PlayerSnapshot {
+                    position: transform.translation,
+                    yaw: input.yaw,
+                    pitch: input.pitch,
+                    velocity,
+                    grounded,
+                };
+
+                Some((*network_entity, QuantizedPlayer::from(&player)))
+            })
+            .collect(),
+    };
+
+    for client_id in server.clients_id() {
+        if !lobby.accepted.contains_key(&client_id) {
+            continue;
+        }
+
+        let last_input = lobby
+            .players
+            .get(&client_id)
+            .and_then(|entity| query.get(*entity).ok())
+            .and_then(|(_, _, _, _, player_inputs, _)| player_inputs.last_processed);
+
+        let client_snapshots = history.clients.entry(client_id).or_default();
+        let baseline = client_snapshots.baseline();
+        let (players, removed) = snapshot.delta_from(baseline.map(|(_, baseline)| baseline));
+
+        let message = Snapshot {
+            tick: tick.0,
+            baseline: baseline.map(|(tick, _)| tick),
+            last_input,
+            players,
+            removed,
+        };
+        *report.snapshot_bytes.entry(client_id).or_default() +=
+            bincode::serialized_size(&message).unwrap() as usize;
+        server.send(client_id, &message);
+
+        client_snapshots.push(tick.0, snapshot.clone());
+    }
+}
+
+/// Tells clients about replicated entities that were despawned on the server.
+pub fn replicate_despawns(
+    mut removed: RemovedComponents<NetworkEntity>,
+    mut entity_map: ResMut<NetworkEntityMap>,
+    mut server: NetworkServer,
+) {
+    for entity in removed.read() {
+        if let Some(network_entity) = entity_map.remove_local(entity) {
+            server.broadcast(&ServerMessage::Despawn {
+                entity: network_entity,
+            });
+        }
+    }
+}
+
+pub fn report_bandwidth(
+    time: Res<Time>,
+    server: Res<RenetServer>,
+    mut report: ResMut<BandwidthReport>,
+) {
+    if !report.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let seconds = report.timer.duration().as_secs_f64();
+    for client_id in server.clients_id() {
+        let snapshot_bytes = report.snapshot_bytes.get(&client_id).copied().unwrap_or(0);
+        if let Ok(info) = server.network_info(client_id) {
+            println!(
+                "Client {}: snapshots {:.1} KB/s, sent {:.1} KB/s, received {:.1} KB/s, rtt {:.0} ms, loss {:.1}%",
+                client_id,
+                snapshot_bytes as f64 / seconds / 1024.0,
+                info.bytes_sent_per_second / 1024.0,
+                info.bytes_received_per_second / 1024.0,
+                info.rtt * 1000.0,
+                info.packet_loss * 100.0,
+            );
         }
     }
+    report.snapshot_bytes.clear();
 }
@@ -0,0 +1,80 @@
+use crate::controller::FpsControllerPlugin;
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+/// Ticks per second until the server tells the client its actual rate.
+pub const DEFAULT_TICK_RATE: f64 = 60.0;
+
+/// Number of fixed simulation steps run so far. Snapshots and inputs refer to these ticks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Resource)]
+pub struct SimulationTick(pub u64);
+
+/// Stages of a simulation tick in `FixedUpdate`, all of them before the physics step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SystemSet)]
+pub enum SimulationSet {
+    /// Advances the [`SimulationTick`].
+    Tick,
+    /// Feeds this tick's input into the controllers.
+    Input,
+    /// Turns input into controller movement.
+    Movement,
+}
+
+/// Runs gameplay simulation, character controllers and physics at a fixed tick rate, the same way
+/// on the client and on the server.
+pub struct SimulationPlugin {
+    pub tick_rate: f64,
+}
+
+impl Default for SimulationPlugin {
+    fn default() -> Self {
+        Self {
+            tick_rate: DEFAULT_TICK_RATE,
+        }
+    }
+}
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        // The configuration must exist before the physics plugin is built, which would otherwise
+        // insert a variable timestep.
+        app.insert_resource(Time::<Fixed>::from_hz(self.tick_rate))
+            .insert_resource(rapier_configuration(self.tick_rate))
+            .add_plugins(RapierPhysicsPlugin::<NoUserData>::default().in_fixed_schedule())
+            .add_plugins(FpsControllerPlugin)
+            .init_resource::<SimulationTick>()
+            .configure_sets(
+                FixedUpdate,
+                (
+                    SimulationSet::Tick,
+                    SimulationSet::Input,
+                    SimulationSet::Movement,
+                )
+                    .chain()
+                    .before(PhysicsSet::SyncBackend),
+            )
+            .add_systems(FixedUpdate, advance_tick.in_set(SimulationSet::Tick));
+    }
+}
+
+/// Switches the running simulation to another tick rate.
+pub fn set_tick_rate(world: &mut World, tick_rate: f64) {
+    world
+        .resource_mut::<Time<Fixed>>()
+        .set_timestep_hz(tick_rate);
+    world.insert_resource(rapier_configuration(tick_rate));
+}
+
+fn rapier_configuration(tick_rate: f64) -> RapierConfiguration {
+    RapierConfiguration {
+        timestep_mode: TimestepMode::Fixed {
+            dt: 1.0 / tick_rate as f32,
+            substeps: 1,
+        },
+        ..default()
+    }
+}
+
+fn advance_tick(mut tick: ResMut<SimulationTick>) {
+    tick.0 += 1;
+}
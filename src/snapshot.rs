@@ -0,0 +1,254 @@
+use crate::{NetworkEntity, PlayerSnapshot};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    f32::consts::{PI, TAU},
+};
+
+/// Fixed-point steps per meter, about 4mm of precision.
+const POSITION_SCALE: f32 = 256.0;
+/// Fixed-point steps per meter per second, up to 512m/s.
+const VELOCITY_SCALE: f32 = 64.0;
+const ANGLE_STEPS: f32 = 65536.0;
+
+/// A player snapshot reduced to fixed-point values, so unchanged fields compare equal.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuantizedPlayer {
+    pub position: [i32; 3],
+    /// Yaw and pitch packed into 16 bits each.
+    pub rotation: [u16; 2],
+    pub velocity: [i16; 3],
+    pub grounded: bool,
+}
+
+impl From<&PlayerSnapshot> for QuantizedPlayer {
+    fn from(snapshot: &PlayerSnapshot) -> Self {
+        let position = (snapshot.position * POSITION_SCALE).round();
+        let velocity = (snapshot.velocity * VELOCITY_SCALE).round();
+
+        Self {
+            position: [position.x as i32, position.y as i32, position.z as i32],
+            rotation: [quantize_angle(snapshot.yaw), quantize_angle(snapshot.pitch)],
+            velocity: [velocity.x as i16, velocity.y as i16, velocity.z as i16],
+            grounded: snapshot.grounded,
+        }
+    }
+}
+
+impl From<&QuantizedPlayer> for PlayerSnapshot {
+    fn from(quantized: &QuantizedPlayer) -> Self {
+        let [x, y, z] = quantized.position;
+        let [vx, vy, vz] = quantized.velocity;
+        let [yaw, pitch] = quantized.rotation;
+
+        Self {
+            position: Vec3::new(x as f32, y as f32, z as f32) / POSITION_SCALE,
+            yaw: dequantize_angle(yaw),
+            pitch: dequantize_angle(pitch),
+            velocity: Vec3::new(vx as f32, vy as f32, vz as f32) / VELOCITY_SCALE,
+            grounded: quantized.grounded,
+        }
+    }
+}
+
+fn quantize_angle(angle: f32) -> u16 {
+    ((angle.rem_euclid(TAU) / TAU) * ANGLE_STEPS).round() as u32 as u16
+}
+
+fn dequantize_angle(angle: u16) -> f32 {
+    let angle = angle as f32 / ANGLE_STEPS * TAU;
+    if angle > PI {
+        angle - TAU
+    } else {
+        angle
+    }
+}
+
+/// Fields of a player that changed since the baseline, `None` when unchanged.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerDelta {
+    pub entity: NetworkEntity,
+    pub position: Option<[i32; 3]>,
+    pub rotation: Option<[u16; 2]>,
+    pub velocity: Option<[i16; 3]>,
+    pub grounded: Option<bool>,
+}
+
+/// Full quantized state of every replicated player at one tick.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WorldSnapshot {
+    pub players: HashMap<NetworkEntity, QuantizedPlayer>,
+}
+
+impl WorldSnapshot {
+    /// Changes needed to turn `baseline` into `self`. Without a baseline every field is sent.
+    pub fn delta_from(
+        &self,
+        baseline: Option<&WorldSnapshot>,
+    ) -> (Vec<PlayerDelta>, Vec<NetworkEntity>) {
+        let players = self
+            .players
+            .iter()
+            .filter_map(|(entity, player)| {
+                let previous = baseline.and_then(|baseline| baseline.players.get(entity));
+                let delta = PlayerDelta {
+                    entity: *entity,
+                    position: changed(previous.map(|p| p.position), player.position),
+                    rotation: changed(previous.map(|p| p.rotation), player.rotation),
+                    velocity: changed(previous.map(|p| p.velocity), player.velocity),
+                    grounded: changed(previous.map(|p| p.grounded), player.grounded),
+                };
+
+                let unchanged = delta.position.is_none()
+                    && delta.rotation.is_none()
+                    && delta.velocity.is_none()
+                    && delta.grounded.is_none();
+                (!unchanged).then_some(delta)
+            })
+            .collect();
+
+        let removed = baseline
+            .map(|baseline| {
+                baseline
+                    .players
+                    .keys()
+                    .filter(|entity| !self.players.contains_key(entity))
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        (players, removed)
+    }
+
+    /// Rebuilds the full snapshot from a baseline and the changes sent against it.
+    pub fn apply_delta(
+        baseline: Option<&WorldSnapshot>,
+        changed: &[PlayerDelta],
+        removed: &[NetworkEntity],
+    ) -> WorldSnapshot {
+        let mut snapshot = baseline.cloned().unwrap_or_default();
+
+        for entity in removed {
+            snapshot.players.remove(entity);
+        }
+
+        for delta in changed {
+            let player = snapshot.players.entry(delta.entity).or_default();
+            if let Some(position) = delta.position {
+                player.position = position;
+            }
+            if let Some(rotation) = delta.rotation {
+                player.rotation = rotation;
+            }
+            if let Some(velocity) = delta.velocity {
+                player.velocity = velocity;
+            }
+            if let Some(grounded) = delta.grounded {
+                player.grounded = grounded;
+            }
+        }
+
+        snapshot
+    }
+}
+
+fn changed<T: PartialEq + Copy>(previous: Option<T>, current: T) -> Option<T> {
+    (previous != Some(current)).then_some(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(x: f32, yaw: f32) -> PlayerSnapshot {
+        PlayerSnapshot {
+            position: Vec3::new(x, 1.5, -3.25),
+            yaw,
+            pitch: -0.4,
+            velocity: Vec3::new(2.0, -9.81, 0.5),
+            grounded: true,
+        }
+    }
+
+    fn world(players: &[(u32, PlayerSnapshot)]) -> WorldSnapshot {
+        WorldSnapshot {
+            players: players
+                .iter()
+                .map(|(id, player)| (NetworkEntity(*id), QuantizedPlayer::from(player)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn quantization_stays_within_precision() {
+        let original = player(12.3456, 1.234);
+        let restored = PlayerSnapshot::from(&QuantizedPlayer::from(&original));
+
+        assert!(
+            (restored.position - original.position).abs().max_element() <= 0.5 / POSITION_SCALE
+        );
+        assert!(
+            (restored.velocity - original.velocity).abs().max_element() <= 0.5 / VELOCITY_SCALE
+        );
+        assert!((restored.yaw - original.yaw).abs() <= TAU / ANGLE_STEPS);
+        assert!((restored.pitch - original.pitch).abs() <= TAU / ANGLE_STEPS);
+        assert_eq!(restored.grounded, original.grounded);
+    }
+
+    #[test]
+    fn angles_wrap_into_half_turn_range() {
+        let angle = dequantize_angle(quantize_angle(-3.0 * PI / 2.0));
+        assert!((angle - PI / 2.0).abs() <= TAU / ANGLE_STEPS);
+        assert_eq!(quantize_angle(TAU), quantize_angle(0.0));
+    }
+
+    #[test]
+    fn delta_round_trips_against_baseline() {
+        let baseline = world(&[
+            (1, player(0.0, 0.0)),
+            (2, player(5.0, 1.0)),
+            (3, player(8.0, 2.0)),
+        ]);
+        let mut moved = player(0.0, 0.0);
+        moved.position.x = 0.5;
+        let current = world(&[(1, moved), (2, player(5.0, 1.0)), (4, player(-1.0, -2.0))]);
+
+        let (changed, removed) = current.delta_from(Some(&baseline));
+
+        assert_eq!(changed.len(), 2, "unchanged players are not sent");
+        let delta = changed
+            .iter()
+            .find(|delta| delta.entity == NetworkEntity(1))
+            .unwrap();
+        assert!(delta.position.is_some());
+        assert_eq!(
+            (delta.rotation, delta.velocity, delta.grounded),
+            (None, None, None)
+        );
+        assert_eq!(removed, vec![NetworkEntity(3)]);
+        assert_eq!(
+            WorldSnapshot::apply_delta(Some(&baseline), &changed, &removed),
+            current
+        );
+    }
+
+    #[test]
+    fn delta_without_baseline_sends_everything() {
+        let current = world(&[(1, player(0.0, 0.0)), (2, player(5.0, 1.0))]);
+
+        let (changed, removed) = current.delta_from(None);
+
+        assert_eq!(changed.len(), 2);
+        assert!(changed.iter().all(|delta| delta.position.is_some()
+            && delta.rotation.is_some()
+            && delta.velocity.is_some()
+            && delta.grounded.is_some()));
+        assert!(removed.is_empty());
+        assert_eq!(
+            WorldSnapshot::apply_delta(None, &changed, &removed),
+            current
+        );
+    }
+}